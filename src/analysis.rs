@@ -0,0 +1,116 @@
+// || shree ganesh ||
+//
+// Once the runtime runs out of active pairs there is no way to tell a net that
+// finished its work from one that silently got stuck. This file walks the
+// final net and looks for the configurations that can never reduce:
+//
+// Two principal ports facing each other with no rule (or never queued)
+// Vicious circles: closed chains where every node's principal port points
+// into an auxiliary port of the next node, so none of them can ever meet
+// a partner principal to principal
+
+use crate::net::{Net, Node, NodeId};
+use std::collections::HashSet;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StuckTerm {
+    NoRule { a: NodeId, b: NodeId }, // principal-to-principal pair with no interaction rule
+    Unqueued { a: NodeId, b: NodeId }, // has a rule but was never put in the active pairs
+    ViciousCircle { nodes: Vec<NodeId> }, // nodes in the order their principal ports point
+}
+
+impl fmt::Display for StuckTerm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StuckTerm::NoRule { a, b } => {
+                write!(f, "no rule for principal pair {} <-> {}", a, b)
+            }
+            StuckTerm::Unqueued { a, b } => {
+                write!(f, "principal pair {} <-> {} was never scheduled", a, b)
+            }
+            StuckTerm::ViciousCircle { nodes } => {
+                let ids: Vec<String> = nodes.iter().map(|id| id.to_string()).collect();
+                write!(f, "vicious circle through nodes {}", ids.join(" -> "))
+            }
+        }
+    }
+}
+
+// Scans every live node of the net and reports everything that can never reduce
+pub fn find_stuck_terms(net: &Net) -> Vec<StuckTerm> {
+    let mut stuck = find_stuck_pairs(net);
+    stuck.extend(find_vicious_circles(net));
+    stuck
+}
+
+fn find_stuck_pairs(net: &Net) -> Vec<StuckTerm> {
+    let queued: HashSet<(NodeId, NodeId)> = net
+        .active_pairs()
+        .flat_map(|r| [(r.a, r.b), (r.b, r.a)])
+        .collect();
+
+    // Definition bodies are copied, never reduced in place
+    let templates = net.template_nodes();
+
+    let mut stuck = Vec::new();
+    for a in net.live_nodes() {
+        let principal = net.port(a, 0);
+        // Each pair is seen from both ends, only report it once
+        if principal.is_null() || principal.slot != 0 || principal.node < a {
+            continue;
+        }
+        let b = principal.node;
+        // The result sitting on the root is not stuck, it is the answer
+        if matches!(net.get_node(a), Node::Root { .. })
+            || matches!(net.get_node(b), Node::Root { .. })
+        {
+            continue;
+        }
+        if !net.is_redex(a, b) {
+            stuck.push(StuckTerm::NoRule { a, b });
+        } else if !queued.contains(&(a, b)) && !templates.contains(&a) {
+            stuck.push(StuckTerm::Unqueued { a, b });
+        }
+    }
+    stuck
+}
+
+// Following the principal port of each node gives every node at most one
+// successor, so the cycles can be found with a single walk per node
+pub fn find_vicious_circles(net: &Net) -> Vec<StuckTerm> {
+    let mut circles = Vec::new();
+    let mut done: HashSet<NodeId> = HashSet::new();
+
+    for start in net.live_nodes() {
+        let mut path: Vec<NodeId> = Vec::new();
+        let mut on_path: HashSet<NodeId> = HashSet::new();
+        let mut current = start;
+
+        loop {
+            if done.contains(&current) {
+                break;
+            }
+            if on_path.contains(&current) {
+                // The walk came back onto itself: everything from there on is the circle
+                let begin = path.iter().position(|&id| id == current).unwrap();
+                circles.push(StuckTerm::ViciousCircle {
+                    nodes: path[begin..].to_vec(),
+                });
+                break;
+            }
+            path.push(current);
+            on_path.insert(current);
+
+            let principal = net.port(current, 0);
+            // A free principal or a principal pair ends the chain
+            if principal.is_null() || principal.slot == 0 {
+                break;
+            }
+            current = principal.node;
+        }
+
+        done.extend(path);
+    }
+    circles
+}
@@ -0,0 +1,249 @@
+// || shree ganesh ||
+//
+// Batch evaluation: many small, independent nets reduced on a fixed pool of
+// threads. The nets usually come from the same program, so they share one
+// Library of definitions instead of each carrying its own copy. A net only
+// needs its own nodes and the references into the library.
+//
+// Every net is reduced by a single thread with the sequential rules; the
+// parallelism comes from running several nets at once, which suits lots of
+// small expressions better than splitting each one over the workers.
+
+use crate::analysis::find_vicious_circles;
+use crate::host::{self, HostFn};
+use crate::interaction::{NetOps, apply_interaction_result as apply_interaction};
+use crate::net::{Net, Node, NodeId, NodeKind, Port, Redex};
+use crate::parallel::Template;
+use crate::runtime::{CancelToken, RunLimits, RunOutcome, RuntimeStats};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
+
+// Definitions compiled once and read by every net of a batch
+pub struct Library {
+    book: HashMap<String, Template>,
+    hosts: HashMap<String, HostFn>,
+}
+
+impl Library {
+    // Takes the definitions and host functions of `net`; the rest of it is ignored
+    pub fn from_net(net: &Net) -> Library {
+        let book = net
+            .definitions()
+            .map(|(name, root)| (name.to_string(), Template::from_net(net, root)))
+            .collect();
+        let hosts = net
+            .hosts()
+            .map(|(name, host)| (name.to_string(), host.clone()))
+            .collect();
+        Library { book, hosts }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.book.contains_key(name) || self.hosts.contains_key(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.book.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.book.is_empty()
+    }
+}
+
+// What became of one net of the batch
+pub struct BatchResult {
+    pub net: Net, // as far as it got, so it can be read back or run again
+    pub outcome: Result<RunOutcome, String>,
+    pub stats: RuntimeStats,
+}
+
+// Reduces every net on `threads` workers and returns the results in the
+// order the nets were given. The limits apply to each net on its own;
+// cancelling stops the nets being reduced and skips the ones not started.
+pub fn evaluate(
+    library: &Library,
+    nets: Vec<Net>,
+    threads: usize,
+    limits: &RunLimits,
+    cancel: &CancelToken,
+) -> Vec<BatchResult> {
+    let jobs: Vec<Mutex<Option<Net>>> = nets.into_iter().map(|n| Mutex::new(Some(n))).collect();
+    let results: Vec<Mutex<Option<BatchResult>>> = jobs.iter().map(|_| Mutex::new(None)).collect();
+    let next = AtomicUsize::new(0);
+
+    thread::scope(|scope| {
+        for _ in 0..threads.clamp(1, jobs.len().max(1)) {
+            scope.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(job) = jobs.get(i) else {
+                        return;
+                    };
+                    let net = job.lock().unwrap().take().unwrap();
+                    let result = reduce(library, net, limits, cancel);
+                    *results[i].lock().unwrap() = Some(result);
+                }
+            });
+        }
+    });
+
+    results
+        .into_iter()
+        .map(|result| result.into_inner().unwrap().unwrap())
+        .collect()
+}
+
+// The sequential loop of the runtime, instantiating from the library
+fn reduce(
+    library: &Library,
+    mut net: Net,
+    limits: &RunLimits,
+    cancel: &CancelToken,
+) -> BatchResult {
+    let mut stats = RuntimeStats {
+        threads: 1,
+        speedup: 1.0,
+        balance: 1.0,
+        max_nodes: net.node_count(),
+        ..RuntimeStats::default()
+    };
+    let start = Instant::now();
+
+    let stopped = |net: &Net, stats: &RuntimeStats| {
+        if cancel.is_cancelled() {
+            return Some(RunOutcome::Cancelled);
+        }
+        limits.exceeded(stats.steps, net.node_count(), start)
+    };
+    let is_host = |net: &Net, name: &str| net.is_host(name) || library.hosts.contains_key(name);
+
+    let outcome = loop {
+        if net.redex_count() == 0 {
+            // See Runtime::finish
+            break match find_vicious_circles(&net).first() {
+                Some(circle) => Err(format!("Evaluation ended in a {}", circle)),
+                None => Ok(RunOutcome::Normal),
+            };
+        }
+        if let Some(outcome) = stopped(&net, &stats) {
+            break Ok(outcome);
+        }
+
+        // A host call has its argument reduced first, as steps of the loop
+        let redex = *net.active_pairs().next().unwrap();
+        let steps = stats.steps;
+        let mut step = |net: &mut Net, redex| match stopped(net, &stats) {
+            Some(outcome) => Ok(Some(outcome)),
+            None => fire(library, net, redex, &mut stats).map(|_| None),
+        };
+        match host::reduce_argument(&mut net, redex, &is_host, &mut step) {
+            Ok(Some(outcome)) => break Ok(outcome),
+            Err(msg) => break Err(format!("Error during evaluation: {}", msg)),
+            // The limits are looked at again before the call itself
+            Ok(None) if stats.steps > steps => continue,
+            Ok(None) => {}
+        }
+
+        net.next_redex();
+        if let Err(msg) = fire(library, &mut net, redex, &mut stats) {
+            break Err(format!("Error during evaluation: {}", msg));
+        }
+    };
+
+    stats.wall_time = start.elapsed();
+    BatchResult {
+        net,
+        outcome,
+        stats,
+    }
+}
+
+// Applies one interaction and keeps the stats up to date
+fn fire(
+    library: &Library,
+    net: &mut Net,
+    redex: Redex,
+    stats: &mut RuntimeStats,
+) -> Result<(), String> {
+    let expands = matches!(net.get_node(redex.a).kind(), NodeKind::Ref(_))
+        || matches!(net.get_node(redex.b).kind(), NodeKind::Ref(_));
+    let fired = Instant::now();
+    apply_interaction(&mut Linked { net, library }, redex)?;
+    if expands {
+        stats.ref_time += fired.elapsed();
+    } else {
+        stats.rewrite_time += fired.elapsed();
+    }
+    stats.steps += 1;
+    stats.max_nodes = stats.max_nodes.max(net.node_count());
+    Ok(())
+}
+
+// A net whose references unfold from the library. Definitions the net
+// carries itself come first, so a net can still override a library entry.
+struct Linked<'a> {
+    net: &'a mut Net,
+    library: &'a Library,
+}
+
+impl NetOps for Linked<'_> {
+    fn get_node(&self, id: NodeId) -> &Node {
+        self.net.get_node(id)
+    }
+
+    fn port(&self, id: NodeId, slot: usize) -> Port {
+        self.net.port(id, slot)
+    }
+
+    fn connect(&mut self, a: NodeId, a_slot: usize, b: NodeId, b_slot: usize) {
+        self.net.connect(a, a_slot, b, b_slot)
+    }
+
+    fn disconnect(&mut self, id: NodeId, slot: usize) {
+        self.net.disconnect(id, slot)
+    }
+
+    fn create_node(&mut self, node: Node) -> NodeId {
+        self.net.create_node(node)
+    }
+
+    fn free_node(&mut self, id: NodeId) {
+        self.net.free_node(id)
+    }
+
+    fn instantiate(&mut self, name: &str) -> Option<NodeId> {
+        if let Some(root) = self.net.get_definition(name) {
+            return Some(self.net.clone_subgraph(root));
+        }
+        let template = self.library.book.get(name)?;
+        Some(template.instantiate_into(self.net))
+    }
+
+    fn is_host(&self, name: &str) -> bool {
+        self.net.is_host(name) || self.library.hosts.contains_key(name)
+    }
+
+    fn call_host(&mut self, name: &str, arg: Port) -> Result<Port, String> {
+        let library = self.library;
+        let host = match self.net.host(name).or_else(|| library.hosts.get(name)) {
+            Some(host) => host.clone(),
+            None => return Err(self.net.undefined(name)),
+        };
+        host::call(
+            self.net,
+            name,
+            &host,
+            arg,
+            |net, name| net.is_host(name) || library.hosts.contains_key(name),
+            |net, redex| apply_interaction(&mut Linked { net, library }, redex),
+        )
+    }
+
+    fn undefined(&self, name: &str) -> String {
+        self.net.undefined(name)
+    }
+}
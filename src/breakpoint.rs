@@ -0,0 +1,48 @@
+// || shree ganesh ||
+//
+// Breakpoints stop a run as soon as something interesting happens, so a
+// debugger can look at the net at that exact moment and then carry on with
+// another run(). They are checked right after every interaction.
+
+use crate::interaction::Rule;
+use crate::net::{Net, Node, NodeKind};
+use crate::observer::InteractionEvent;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Breakpoint {
+    Expand(String),   // a reference to this definition was unfolded
+    Rule(Rule),       // this rule fired
+    Tag(i32),         // a constructor with this tag was created
+    NodeCount(usize), // the net grew past this many live nodes
+}
+
+impl Breakpoint {
+    // Whether the interaction just applied (the net is already rewritten) hits it
+    pub fn hit(&self, net: &Net, event: &InteractionEvent) -> bool {
+        match self {
+            Breakpoint::Expand(name) => event
+                .kinds
+                .iter()
+                .any(|kind| matches!(kind, NodeKind::Ref(r) if r == name)),
+            Breakpoint::Rule(rule) => {
+                Rule::between(&event.kinds[0], &event.kinds[1]) == Some(*rule)
+            }
+            Breakpoint::Tag(tag) => event.created.iter().any(|&id| {
+                net.is_live(id) && matches!(net.get_node(id), Node::Con { tag: t, .. } if t == tag)
+            }),
+            Breakpoint::NodeCount(max) => net.node_count() > *max,
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Breakpoint::Expand(name) => write!(f, "expand {}", name),
+            Breakpoint::Rule(rule) => write!(f, "rule {}", rule),
+            Breakpoint::Tag(tag) => write!(f, "tag {}", tag),
+            Breakpoint::NodeCount(max) => write!(f, "nodes > {}", max),
+        }
+    }
+}
@@ -0,0 +1,295 @@
+// || shree ganesh ||
+//
+// Checkpoints let a long evaluation be parked on disk and picked up again in
+// another process. Everything that decides what the runtime does next is
+// saved: every node slot (consumed ones too, with the order they will be
+// reused in), the active pairs in queue order, the definitions, the root and
+// the stats (apart from the samples) and the next fresh duplicator label. A
+// resumed runtime therefore allocates the same ids, hands out the same labels
+// and fires the same redexes as the original would have.
+//
+// Host functions are Rust closures and cannot be written out, so only their
+// names are: resuming fails unless each is registered again. The source map
+// is not saved either; give it back too, or undefined references are
+// reported without their place in the source.
+//
+// The file is plain text, one record per line:
+//
+//   vice-checkpoint 4
+//   stats <steps> <max_nodes> <threads> <steals> <speedup> <balance> <wall ns> <ref ns> <rewrite ns> <fuel used>
+//   root <id or ->
+//   labels <next fresh label>
+//   node <id> <kind> <ports...>      ports are node:slot, - when free
+//   dead <id>
+//   free <ids...>                    consumed slots, next to be reused last
+//   def <name> <id>
+//   host <name>
+//   pair <a> <b>
+
+use crate::net::{Net, Node, NodeId, NodeKind, Port, Redex};
+use crate::runtime::RuntimeStats;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+const HEADER: &str = "vice-checkpoint 4";
+
+pub fn save(net: &Net, stats: &RuntimeStats, path: impl AsRef<Path>) -> Result<(), String> {
+    let mut out = vec![HEADER.to_string()];
+    out.push(format!(
+        "stats {} {} {} {} {} {} {} {} {} {}",
+        stats.steps,
+        stats.max_nodes,
+        stats.threads,
+        stats.steals,
+        stats.speedup,
+        stats.balance,
+        stats.wall_time.as_nanos(),
+        stats.ref_time.as_nanos(),
+        stats.rewrite_time.as_nanos(),
+        stats.fuel_used
+    ));
+    out.push(format!(
+        "root {}",
+        net.root().map_or("-".to_string(), |id| id.to_string())
+    ));
+    out.push(format!("labels {}", net.next_label()));
+
+    for id in 0..net.slot_count() {
+        if !net.is_live(id) {
+            out.push(format!("dead {}", id));
+            continue;
+        }
+        let node = net.get_node(id);
+        let ports: Vec<String> = (0..node.arity())
+            .map(|slot| port_str(node.port(slot)))
+            .collect();
+        out.push(format!("node {} {} {}", id, node.kind(), ports.join(" ")));
+    }
+
+    let free: Vec<String> = net.free_list().iter().map(|id| id.to_string()).collect();
+    out.push(format!("free {}", free.join(" ")).trim_end().to_string());
+
+    let mut definitions: Vec<(&str, NodeId)> = net.definitions().collect();
+    definitions.sort();
+    for (name, id) in definitions {
+        out.push(format!("def {} {}", name, id));
+    }
+    let mut hosts: Vec<&str> = net.hosts().map(|(name, _)| name).collect();
+    hosts.sort();
+    for name in hosts {
+        out.push(format!("host {}", name));
+    }
+    for redex in net.active_pairs() {
+        out.push(format!("pair {} {}", redex.a, redex.b));
+    }
+
+    // Written next to the target and renamed over it, so a job preempted
+    // halfway through saving still has its previous checkpoint
+    let path = path.as_ref();
+    let partial = path.with_extension("partial");
+    out.push(String::new());
+    fs::write(&partial, out.join("\n"))
+        .map_err(|e| format!("Cannot write checkpoint {}: {}", partial.display(), e))?;
+    fs::rename(&partial, path)
+        .map_err(|e| format!("Cannot write checkpoint {}: {}", path.display(), e))
+}
+
+// The saved net and stats, with the names of the host functions the net
+// calls, which the caller has to register again
+pub fn load(path: impl AsRef<Path>) -> Result<(Net, RuntimeStats, Vec<String>), String> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read checkpoint {}: {}", path.display(), e))?;
+    parse(&text).map_err(|msg| format!("{}: {}", path.display(), msg))
+}
+
+fn parse(text: &str) -> Result<(Net, RuntimeStats, Vec<String>), String> {
+    let mut lines = text.lines().enumerate();
+    match lines.next() {
+        Some((_, HEADER)) => {}
+        _ => return Err("not a checkpoint file".to_string()),
+    }
+
+    let mut stats = RuntimeStats::default();
+    let mut root = None;
+    let mut next_label = None;
+    let mut hosts = Vec::new();
+    let mut nodes: Vec<Option<Node>> = Vec::new();
+    let mut free = Vec::new();
+    let mut definitions = Vec::new();
+    let mut pairs = Vec::new();
+
+    for (number, line) in lines {
+        let at = |msg: String| format!("line {}: {}", number + 1, msg);
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields[..] {
+            [] => {}
+            [
+                "stats",
+                steps,
+                max_nodes,
+                threads,
+                steals,
+                speedup,
+                balance,
+                wall,
+                refs,
+                rewrites,
+                fuel,
+            ] => {
+                stats = RuntimeStats {
+                    steps: number_of(steps).map_err(at)?,
+                    max_nodes: number_of(max_nodes).map_err(at)?,
+                    threads: number_of(threads).map_err(at)?,
+                    steals: number_of(steals).map_err(at)?,
+                    speedup: speedup
+                        .parse()
+                        .map_err(|_| at(format!("bad speedup {}", speedup)))?,
+                    balance: balance
+                        .parse()
+                        .map_err(|_| at(format!("bad balance {}", balance)))?,
+                    wall_time: duration_of(wall).map_err(at)?,
+                    ref_time: duration_of(refs).map_err(at)?,
+                    rewrite_time: duration_of(rewrites).map_err(at)?,
+                    samples: Vec::new(),
+                    fuel_used: fuel.parse().map_err(|_| at(format!("bad fuel {}", fuel)))?,
+                };
+            }
+            ["root", "-"] => root = None,
+            ["root", id] => root = Some(number_of(id).map_err(at)?),
+            ["labels", next] => {
+                next_label = Some(
+                    next.parse()
+                        .map_err(|_| at(format!("bad label {}", next)))?,
+                )
+            }
+            ["node", id, kind, ref ports @ ..] => {
+                expect_slot(&nodes, id).map_err(at)?;
+                let kind: NodeKind = kind.parse().map_err(at)?;
+                let ports = ports
+                    .iter()
+                    .map(|port| parse_port(port))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(at)?;
+                nodes.push(Some(build_node(kind, &ports).map_err(at)?));
+            }
+            ["dead", id] => {
+                expect_slot(&nodes, id).map_err(at)?;
+                nodes.push(None);
+            }
+            ["free", ref ids @ ..] => {
+                free = ids
+                    .iter()
+                    .map(|id| number_of(id))
+                    .collect::<Result<_, _>>()
+                    .map_err(at)?;
+            }
+            ["def", name, id] => definitions.push((name, number_of(id).map_err(at)?)),
+            ["host", name] => hosts.push(name.to_string()),
+            ["pair", a, b] => pairs.push(Redex {
+                a: number_of(a).map_err(at)?,
+                b: number_of(b).map_err(at)?,
+            }),
+            _ => return Err(at(format!("unexpected record: {}", line))),
+        }
+    }
+
+    // Every id mentioned has to name a slot of the right sort
+    let live = |id: NodeId| nodes.get(id).is_some_and(|node| node.is_some());
+    let dead: HashSet<NodeId> = (0..nodes.len()).filter(|&id| !live(id)).collect();
+    if free.len() != dead.len() || !free.iter().all(|id| dead.contains(id)) {
+        return Err("the free list does not match the dead slots".to_string());
+    }
+    for node in nodes.iter().flatten() {
+        for slot in 0..node.arity() {
+            let port = node.port(slot);
+            if port.is_null() {
+                continue;
+            }
+            if !live(port.node) {
+                return Err(format!("a wire points at missing node {}", port.node));
+            }
+            if nodes[port.node]
+                .as_ref()
+                .is_some_and(|peer| port.slot >= peer.arity())
+            {
+                return Err(format!("a wire points at missing port {}", port_str(port)));
+            }
+        }
+    }
+    let ids = root
+        .iter()
+        .chain(definitions.iter().map(|(_, id)| id))
+        .chain(pairs.iter().flat_map(|r| [&r.a, &r.b]));
+    for &id in ids {
+        if !live(id) {
+            return Err(format!("node {} is referenced but not live", id));
+        }
+    }
+
+    let mut net = Net::new();
+    net.import(nodes, pairs)?;
+    net.set_free_list(free);
+    net.restore_root(root);
+    net.restore_next_label(next_label.ok_or("the next label is missing")?)?;
+    for (name, id) in definitions {
+        net.add_definition(name, id);
+    }
+    Ok((net, stats, hosts))
+}
+
+fn expect_slot(nodes: &[Option<Node>], id: &str) -> Result<(), String> {
+    if number_of(id)? != nodes.len() {
+        return Err(format!("expected slot {}, found {}", nodes.len(), id));
+    }
+    Ok(())
+}
+
+fn build_node(kind: NodeKind, ports: &[Port]) -> Result<Node, String> {
+    let node = match (kind, ports) {
+        (NodeKind::Con(tag), &[a, b, c]) => Node::Con {
+            tag,
+            ports: [a, b, c],
+        },
+        (NodeKind::Dup(label), &[a, b, c]) => Node::Dup {
+            label,
+            ports: [a, b, c],
+        },
+        (NodeKind::Ref(name), &[port]) => Node::Ref { name, port },
+        (NodeKind::Era, &[port]) => Node::Era { port },
+        (NodeKind::Root, &[port]) => Node::Root { port },
+        (kind, ports) => return Err(format!("{} cannot have {} ports", kind, ports.len())),
+    };
+    Ok(node)
+}
+
+fn port_str(port: Port) -> String {
+    if port.is_null() {
+        "-".to_string()
+    } else {
+        format!("{}:{}", port.node, port.slot)
+    }
+}
+
+fn parse_port(text: &str) -> Result<Port, String> {
+    if text == "-" {
+        return Ok(Port::null());
+    }
+    let (node, slot) = text
+        .split_once(':')
+        .ok_or_else(|| format!("bad port {}", text))?;
+    Ok(Port::new(number_of(node)?, number_of(slot)?))
+}
+
+fn duration_of(nanos: &str) -> Result<Duration, String> {
+    let nanos: u64 = nanos
+        .parse()
+        .map_err(|_| format!("bad duration {}", nanos))?;
+    Ok(Duration::from_nanos(nanos))
+}
+
+fn number_of(text: &str) -> Result<usize, String> {
+    text.parse().map_err(|_| format!("bad number {}", text))
+}
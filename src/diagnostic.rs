@@ -0,0 +1,251 @@
+// || shree ganesh ||
+//
+// Errors that point into source text. A Diagnostic knows the file, the line
+// and the column it is about, and keeps the offending line so that it can be
+// shown on its own, long after the source is gone:
+//
+//   error: undefined name `sqaure`
+//    --> examples/area.hvm:4:8
+//     |
+//   4 | main = sqaure 3
+//     |        ^^^^^^
+//
+// Display gives the same thing on one line ("examples/area.hvm:4:8: ..."),
+// and to_json() a flat object for editors and other tools. A note is shown
+// the same way; it tells what the compiler did (linear.rs) rather than what
+// went wrong.
+//
+// Once a program is compiled, the net keeps a SourceMap of where every
+// reference was written, so that a reference failing at run time (nothing
+// by that name was registered, say) is reported at its place in the source
+// rather than as a node id.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+// Byte offsets into a source text, `end` excluded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Source {
+    pub name: String, // the path, or something like "<input>"
+    pub text: String,
+}
+
+impl Source {
+    pub fn new(name: &str, text: &str) -> Self {
+        Source {
+            name: name.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    // Line and column of a byte offset, both counted from 1, the column in
+    // characters
+    pub fn location(&self, offset: usize) -> (usize, usize) {
+        let before = &self.text[..offset.min(self.text.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+        (line, column)
+    }
+
+    // The text of a line, counted from 1, without its line break
+    pub fn line(&self, line: usize) -> &str {
+        self.text.lines().nth(line - 1).unwrap_or("")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Note,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Note => write!(f, "note"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub file: String,
+    pub line: usize, // 0 when the error is about the file as a whole
+    pub column: usize,
+    pub end_line: usize,
+    pub end_column: usize, // just past the last character
+    pub message: String,
+    snippet: String, // the source line the error starts on
+}
+
+impl Diagnostic {
+    pub fn new(source: &Source, span: Span, message: impl Into<String>) -> Self {
+        let (line, column) = source.location(span.start);
+        let (end_line, end_column) = source.location(span.end.max(span.start));
+        Diagnostic {
+            severity: Severity::Error,
+            file: source.name.clone(),
+            line,
+            column,
+            end_line,
+            end_column,
+            message: message.into(),
+            snippet: source.line(line).to_string(),
+        }
+    }
+
+    // An error with no place in the text, like a file that cannot be read
+    pub fn in_file(file: &str, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            file: file.to_string(),
+            line: 0,
+            column: 0,
+            end_line: 0,
+            end_column: 0,
+            message: message.into(),
+            snippet: String::new(),
+        }
+    }
+
+    pub fn note(source: &Source, span: Span, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Note,
+            ..Diagnostic::new(source, span, message)
+        }
+    }
+
+    // The message, where it is, and the source line with carets under the
+    // offending part (up to the end of the line, if it spans more than one)
+    pub fn render(&self) -> String {
+        let mut out = format!("{}: {}\n", self.severity, self.message);
+        if self.line == 0 {
+            out.push_str(&format!(" --> {}\n", self.file));
+            return out;
+        }
+        let number = self.line.to_string();
+        let gutter = " ".repeat(number.len());
+        let line_len = self.snippet.chars().count();
+        let last = if self.end_line == self.line {
+            self.end_column.min(line_len + 1)
+        } else {
+            line_len + 1
+        };
+        let width = last.saturating_sub(self.column).max(1);
+        // Keep the tabs of the source line so the carets land where they should
+        let indent: String = self
+            .snippet
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        out.push_str(&format!(
+            "{}--> {}:{}:{}\n",
+            gutter, self.file, self.line, self.column
+        ));
+        out.push_str(&format!("{} |\n", gutter));
+        out.push_str(&format!("{} | {}\n", number, self.snippet));
+        out.push_str(&format!("{} | {}{}\n", gutter, indent, "^".repeat(width)));
+        out
+    }
+
+    // {"severity":...,"file":...,"line":...,"column":...,"end_line":...,"end_column":...,"message":...}
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"severity\":\"{}\",\"file\":{},\"line\":{},\"column\":{},\"end_line\":{},\"end_column\":{},\"message\":{}}}",
+            self.severity,
+            json_string(&self.file),
+            self.line,
+            self.column,
+            self.end_line,
+            self.end_column,
+            json_string(&self.message)
+        )
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            0 => write!(f, "{}: ", self.file)?,
+            _ => write!(f, "{}:{}:{}: ", self.file, self.line, self.column)?,
+        }
+        if self.severity == Severity::Note {
+            write!(f, "note: ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Where the references in a compiled program were written
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    references: HashMap<String, Vec<Reference>>, // by the name referred to
+}
+
+#[derive(Debug, Clone)]
+struct Reference {
+    from: String, // the definition it is written in
+    source: Arc<Source>,
+    span: Span,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_reference(&mut self, name: &str, from: &str, source: &Arc<Source>, span: Span) {
+        self.references
+            .entry(name.to_string())
+            .or_default()
+            .push(Reference {
+                from: from.to_string(),
+                source: source.clone(),
+                span,
+            });
+    }
+
+    // Points at the first place `name` is referred to, for a reference that
+    // turned out to have nothing behind it
+    pub fn undefined(&self, name: &str) -> Option<Diagnostic> {
+        let reference = self.references.get(name)?.first()?;
+        let message = format!(
+            "undefined function `{}`, referenced in `{}`",
+            name, reference.from
+        );
+        Some(Diagnostic::new(&reference.source, reference.span, message))
+    }
+}
@@ -0,0 +1,289 @@
+// || shree ganesh ||
+//
+// The canonical layout of .hvm sources, so that generated programs and
+// hand-written ones look alike and diff cleanly. format() prints a parsed
+// Program back as source:
+//
+//   - definitions stay in the order they were written; a blank line
+//     between two of them is kept, several blank lines become one
+//   - comments stay above the definition they were written above, and a
+//     comment after a definition, on its last line, stays after it. The
+//     ones inside a definition move up above it; moved_comments() lists
+//     those that were at the end of a line of code, and `vice fmt` shows
+//     them as notes
+//   - a definition fits on one line if it can, in WIDTH characters;
+//     otherwise its body starts on the next line, indented by INDENT, and
+//     whatever still does not fit is broken up the same way
+//   - lambdas are written with λ, binders merged (λx y. body), and only the
+//     parentheses the parser needs are kept, plus the ones around a lambda
+//     or let that is the last argument of an application
+//
+// Formatting its own output gives back the same text.
+
+use crate::diagnostic::Diagnostic;
+use crate::parser::{Definition, Expr, Op, Program, parse_file};
+use std::fs;
+use std::path::Path;
+
+const WIDTH: usize = 80;
+const INDENT: usize = 4;
+
+// How tightly a form binds; a subterm printed where a tighter one is
+// needed goes in parentheses
+const TERM: u8 = 0; // lambda and let
+const COMPARISON: u8 = 1;
+const SUM: u8 = 2;
+const PRODUCT: u8 = 3;
+const APPLICATION: u8 = 4;
+const ATOM: u8 = 5;
+
+pub fn format(program: &Program) -> String {
+    let mut out = String::new();
+    for definition in &program.definitions {
+        for line in &definition.leading {
+            out.push_str(line);
+            out.push('\n');
+        }
+        out.push_str(&format_definition(definition));
+        if let Some(comment) = &definition.trailing {
+            out.push(' ');
+            out.push_str(comment);
+        }
+        out.push('\n');
+    }
+    for line in &program.trailing {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+// A note for each comment that was at the end of a line of code inside a
+// definition, and that formatting moves to the line above the definition
+pub fn moved_comments(program: &Program) -> Vec<Diagnostic> {
+    program
+        .definitions
+        .iter()
+        .flat_map(|definition| {
+            definition.moved.iter().map(|&span| {
+                let msg = format!("comment moved above `{}`", definition.name);
+                Diagnostic::note(&program.source, span, msg)
+            })
+        })
+        .collect()
+}
+
+// Whether the file is already formatted: None if so, otherwise the text it
+// should have. Either way, the comments formatting moves (moved_comments).
+pub fn check_file(
+    path: impl AsRef<Path>,
+) -> Result<(Option<String>, Vec<Diagnostic>), Vec<Diagnostic>> {
+    let program = parse_file(path)?;
+    let formatted = format(&program);
+    let moved = moved_comments(&program);
+    Ok((
+        (formatted != program.source.text).then_some(formatted),
+        moved,
+    ))
+}
+
+// Formats the file in place. Returns whether it changed, and the comments
+// that moved.
+pub fn format_file(path: impl AsRef<Path>) -> Result<(bool, Vec<Diagnostic>), Vec<Diagnostic>> {
+    let path = path.as_ref();
+    let (Some(formatted), moved) = check_file(path)? else {
+        return Ok((false, Vec::new()));
+    };
+    fs::write(path, formatted).map_err(|e| {
+        let name = path.display().to_string();
+        vec![Diagnostic::in_file(
+            &name,
+            format!("cannot write the file: {}", e),
+        )]
+    })?;
+    Ok((true, moved))
+}
+
+fn format_definition(definition: &Definition) -> String {
+    let mut head = definition.name.clone();
+    for (param, _) in &definition.params {
+        head.push(' ');
+        head.push_str(param);
+    }
+    head.push_str(" =");
+
+    let body = flat(&definition.body, TERM);
+    if width(&head) + 1 + width(&body) <= WIDTH {
+        return format!("{} {}", head, body);
+    }
+    format!(
+        "{}\n{}{}",
+        head,
+        " ".repeat(INDENT),
+        layout(&definition.body, TERM, INDENT, INDENT)
+    )
+}
+
+// ---- One line ----
+
+fn flat(expr: &Expr, context: u8) -> String {
+    let text = match expr {
+        Expr::Name(name, _) => name.clone(),
+        Expr::Num(n) => n.to_string(),
+        Expr::Lam(..) => {
+            let (vars, body) = binders(expr);
+            format!("λ{}. {}", vars.join(" "), flat(body, TERM))
+        }
+        Expr::Let(var, _, value, body) => {
+            format!("let {} = {}; {}", var, flat(value, TERM), flat(body, TERM))
+        }
+        Expr::App(..) => {
+            let (head, args) = spine(expr);
+            let mut text = flat(head, ATOM);
+            for arg in args {
+                text.push(' ');
+                text.push_str(&flat(arg, ATOM));
+            }
+            text
+        }
+        Expr::Op(op, left, right) => {
+            let (left_context, right_context) = operands(*op);
+            format!(
+                "{} {} {}",
+                flat(left, left_context),
+                op,
+                flat(right, right_context)
+            )
+        }
+        Expr::Con(tag, left, right) => {
+            format!("#{}{{{} {}}}", tag, flat(left, ATOM), flat(right, ATOM))
+        }
+    };
+    if precedence(expr) < context {
+        format!("({})", text)
+    } else {
+        text
+    }
+}
+
+// ---- Over several lines ----
+
+// `expr` laid out to start at column `column` of a line indented by
+// `indent`, broken over more lines where it does not fit
+fn layout(expr: &Expr, context: u8, column: usize, indent: usize) -> String {
+    let text = flat(expr, context);
+    if column + width(&text) <= WIDTH {
+        return text;
+    }
+    if precedence(expr) < context {
+        return format!("({})", layout(expr, TERM, column + 1, indent));
+    }
+
+    let inner = indent + INDENT;
+    let newline = |indent: usize| format!("\n{}", " ".repeat(indent));
+    match expr {
+        Expr::Lam(..) => {
+            let (vars, body) = binders(expr);
+            format!(
+                "λ{}.{}{}",
+                vars.join(" "),
+                newline(inner),
+                layout(body, TERM, inner, inner)
+            )
+        }
+        // Each let on a line of its own, the body after them
+        Expr::Let(var, _, value, body) => {
+            let head = format!("let {} = ", var);
+            let value = layout(value, TERM, column + width(&head), inner);
+            format!(
+                "{}{};{}{}",
+                head,
+                value,
+                newline(indent),
+                layout(body, TERM, indent, indent)
+            )
+        }
+        // One argument per line
+        Expr::App(..) => {
+            let (head, args) = spine(expr);
+            let mut text = layout(head, ATOM, column, indent);
+            for arg in args {
+                text.push_str(&newline(inner));
+                text.push_str(&layout(arg, ATOM, inner, inner));
+            }
+            text
+        }
+        // The operator starts the next line
+        Expr::Op(op, left, right) => {
+            let (left_context, right_context) = operands(*op);
+            let prefix = format!("{} ", op);
+            format!(
+                "{}{}{}{}",
+                layout(left, left_context, column, indent),
+                newline(inner),
+                prefix,
+                layout(right, right_context, inner + width(&prefix), inner)
+            )
+        }
+        Expr::Con(tag, left, right) => {
+            let head = format!("#{}{{", tag);
+            let left = layout(left, ATOM, column + width(&head), inner);
+            format!(
+                "{}{}{}{}}}",
+                head,
+                left,
+                newline(inner),
+                layout(right, ATOM, inner, inner)
+            )
+        }
+        Expr::Name(..) | Expr::Num(_) => text,
+    }
+}
+
+// ---- Shapes ----
+
+fn precedence(expr: &Expr) -> u8 {
+    match expr {
+        Expr::Lam(..) | Expr::Let(..) => TERM,
+        Expr::Op(Op::Add | Op::Sub, ..) => SUM,
+        Expr::Op(Op::Mul, ..) => PRODUCT,
+        Expr::Op(..) => COMPARISON,
+        Expr::App(..) => APPLICATION,
+        Expr::Name(..) | Expr::Num(_) | Expr::Con(..) => ATOM,
+    }
+}
+
+// What each side of an operator has to bind at least as tightly as. Sums
+// and products lean to the left; comparisons do not chain.
+fn operands(op: Op) -> (u8, u8) {
+    match op {
+        Op::Add | Op::Sub => (SUM, PRODUCT),
+        Op::Mul => (PRODUCT, APPLICATION),
+        _ => (SUM, SUM),
+    }
+}
+
+// λx. λy. body as ([x, y], body)
+fn binders(mut expr: &Expr) -> (Vec<&str>, &Expr) {
+    let mut vars = Vec::new();
+    while let Expr::Lam(var, _, body) = expr {
+        vars.push(var.as_str());
+        expr = body;
+    }
+    (vars, expr)
+}
+
+// f a b as (f, [a, b])
+fn spine(mut expr: &Expr) -> (&Expr, Vec<&Expr>) {
+    let mut args = Vec::new();
+    while let Expr::App(fun, arg) = expr {
+        args.push(arg.as_ref());
+        expr = fun;
+    }
+    args.reverse();
+    (expr, args)
+}
+
+fn width(text: &str) -> usize {
+    text.chars().count()
+}
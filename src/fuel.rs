@@ -0,0 +1,80 @@
+// || shree ganesh ||
+//
+// Fuel metering, for running programs on somebody else's behalf. Every
+// interaction has a price and the runtime draws it from a tank; once the
+// next interaction costs more than what is left, the run stops with
+// RunOutcome::OutOfFuel. A step limit treats every interaction alike, fuel
+// follows the work actually done: unfolding a reference copies the whole
+// definition, a commutation allocates four nodes, an annihilation none.
+
+use crate::interaction::Rule;
+use crate::net::{Net, Node, NodeId};
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuelCosts {
+    pub annihilate: u64,
+    pub commute: u64,
+    pub erase: u64,
+    pub void: u64,
+    pub expand: u64,          // for every unfolded reference...
+    pub expand_per_node: u64, // ...plus this for every node of the definition
+    pub host_call: u64,       // a call to a host function, on top of reducing its argument
+}
+
+impl Default for FuelCosts {
+    // Roughly the nodes each rule allocates, plus one for firing at all
+    fn default() -> Self {
+        FuelCosts {
+            annihilate: 1,
+            commute: 5,
+            erase: 3,
+            void: 1,
+            expand: 1,
+            expand_per_node: 1,
+            host_call: 100,
+        }
+    }
+}
+
+impl FuelCosts {
+    // The price of firing `a` against `b`, the node on its principal port
+    pub fn price(&self, net: &Net, a: NodeId, b: NodeId) -> u64 {
+        let (node_a, node_b) = (net.get_node(a), net.get_node(b));
+        match Rule::between(&node_a.kind(), &node_b.kind()) {
+            Some(Rule::Annihilate) => self.annihilate,
+            Some(Rule::Commute) => self.commute,
+            Some(Rule::Erase) => self.erase,
+            Some(Rule::Void) => self.void,
+            Some(Rule::Expand) => {
+                let name = match (node_a, node_b) {
+                    (Node::Ref { name, .. }, _) | (_, Node::Ref { name, .. }) => name,
+                    _ => unreachable!("an expansion always involves a reference"),
+                };
+                if net.host(name).is_some() {
+                    return self.host_call;
+                }
+                let size = net
+                    .get_definition(name)
+                    .map_or(0, |root| definition_size(net, root));
+                self.expand + self.expand_per_node * size as u64
+            }
+            None => 0,
+        }
+    }
+}
+
+// Nodes a definition is made of, all of which an expansion copies
+fn definition_size(net: &Net, root: NodeId) -> usize {
+    let mut seen = HashSet::from([root]);
+    let mut stack = vec![root];
+    while let Some(id) = stack.pop() {
+        for slot in 0..net.get_node(id).arity() {
+            let peer = net.port(id, slot);
+            if !peer.is_null() && seen.insert(peer.node) {
+                stack.push(peer.node);
+            }
+        }
+    }
+    seen.len()
+}
@@ -0,0 +1,171 @@
+// || shree ganesh ||
+//
+// Host functions give VORTEX programs a way out of the pure core: I/O,
+// clocks, random numbers. A Rust closure is registered on the net under a
+// reserved name, and a reference to that name applied to an argument
+//
+//   Ref(name) >-< App(argument, result)
+//
+// calls the closure instead of unfolding a definition. The argument is
+// reduced to normal form and read back as a Term, the closure answers with
+// another Term, and that is encoded as a net and plugged in as the result.
+// The argument itself is erased.
+//
+// The runtime and batch.rs reduce the argument before the call fires, as
+// steps of their own that are limited, metered and watched like any other
+// (see reduce_argument). call() still normalizes whatever is left, for
+// callers that do not.
+//
+// A host name wins over a definition of the same name. The parallel
+// runtime cannot read back under its node locks, so a net with host
+// functions is always reduced on one thread. Closures cannot be written
+// to a checkpoint either; register them again on the resumed net.
+
+use crate::net::{Net, Node, NodeId, Port, Redex};
+use crate::readback::read_term_at;
+use crate::term::{LAMBDA_TAG, Term, encode};
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+
+pub type HostFn = Arc<dyn Fn(&Term) -> Result<Term, String> + Send + Sync>;
+
+// Gives up on arguments that do not reach a normal form
+const MAX_ARGUMENT_STEPS: usize = 1_000_000;
+
+// Runs `host` on the value plugged into `arg` and builds the answer in the
+// net. Returns the port carrying the answer, still unplugged. `fire` applies
+// a rule, so that references in the argument unfold from wherever the
+// caller keeps its definitions; `is_host` tells which names it calls out for.
+pub(crate) fn call(
+    net: &mut Net,
+    name: &str,
+    host: &HostFn,
+    arg: Port,
+    is_host: impl Fn(&Net, &str) -> bool,
+    fire: impl FnMut(&mut Net, Redex) -> Result<(), String>,
+) -> Result<Port, String> {
+    normalize_argument(net, arg, is_host, fire)?;
+    let argument = read_term_at(net, arg).map_err(|msg| {
+        format!(
+            "Cannot read the argument of host function {}: {}",
+            name, msg
+        )
+    })?;
+    let answer =
+        host(&argument).map_err(|msg| format!("Host function {} failed: {}", name, msg))?;
+    encode(net, &answer)
+        .map_err(|msg| format!("Host function {} returned {}: {}", name, answer, msg))
+}
+
+// The port the argument is plugged into, when `redex` calls a host function
+pub(crate) fn argument(net: &Net, redex: Redex, is_host: impl Fn(&str) -> bool) -> Option<Port> {
+    let app = match (net.get_node(redex.a), net.get_node(redex.b)) {
+        (Node::Ref { name, .. }, _) if is_host(name) => redex.b,
+        (_, Node::Ref { name, .. }) if is_host(name) => redex.a,
+        _ => return None,
+    };
+    matches!(net.get_node(app), Node::Con { tag, .. } if *tag == LAMBDA_TAG)
+        .then_some(Port::new(app, 1))
+}
+
+// Takes the whole queue out of the net, split into the redexes the argument
+// on `arg` is made of and the others. Both keep their order.
+pub(crate) fn split_queue(net: &mut Net, arg: Port) -> (VecDeque<Redex>, VecDeque<Redex>) {
+    let region = reachable(net, arg);
+    net.take_redexes()
+        .into_iter()
+        .partition(|redex| region.contains(&redex.a) || region.contains(&redex.b))
+}
+
+// When `redex` calls a host function, fires the redexes its argument is
+// made of until there are none left. Each interaction only touches the
+// argument, so the redexes it queues belong to it too. `step` fires one of
+// them, or tells why it will not; the reduction stops there and leaves the
+// rest queued. A host call inside the argument gets the same treatment
+// before it fires.
+pub(crate) fn reduce_argument<T>(
+    net: &mut Net,
+    redex: Redex,
+    is_host: &impl Fn(&Net, &str) -> bool,
+    step: &mut impl FnMut(&mut Net, Redex) -> Result<Option<T>, String>,
+) -> Result<Option<T>, String> {
+    match argument(net, redex, |name| is_host(net, name)) {
+        Some(arg) => reduce_at(net, arg, is_host, step),
+        None => Ok(None),
+    }
+}
+
+fn reduce_at<T>(
+    net: &mut Net,
+    arg: Port,
+    is_host: &impl Fn(&Net, &str) -> bool,
+    step: &mut impl FnMut(&mut Net, Redex) -> Result<Option<T>, String>,
+) -> Result<Option<T>, String> {
+    let (mut pending, rest) = split_queue(net, arg);
+    let mut reduce = || {
+        while let Some(redex) = pending.pop_front() {
+            // A host call inside the argument looks for its own argument
+            // in the queue
+            if argument(net, redex, |name| is_host(net, name)).is_some() {
+                net.queue_redexes(pending.drain(..));
+            }
+            let stopped = match reduce_argument(net, redex, is_host, step) {
+                Ok(None) => step(net, redex),
+                stopped => stopped,
+            };
+            pending.extend(net.take_redexes());
+            if let Some(reason) = stopped? {
+                pending.push_front(redex);
+                return Ok(Some(reason));
+            }
+        }
+        Ok(None)
+    };
+    let result = reduce();
+    let queued = net.take_redexes();
+    net.queue_redexes(rest.into_iter().chain(pending).chain(queued));
+    result
+}
+
+// Whatever of the argument is still to reduce, for callers that did not
+// (see reduce_argument). These interactions happen inside the host call,
+// so nobody counts them as steps; there can only be so many.
+fn normalize_argument(
+    net: &mut Net,
+    arg: Port,
+    is_host: impl Fn(&Net, &str) -> bool,
+    mut fire: impl FnMut(&mut Net, Redex) -> Result<(), String>,
+) -> Result<(), String> {
+    let mut steps = 0;
+    let mut step = |net: &mut Net, redex| {
+        if steps == MAX_ARGUMENT_STEPS {
+            return Ok(Some(()));
+        }
+        steps += 1;
+        fire(net, redex).map(|_| None)
+    };
+    match reduce_at(net, arg, &is_host, &mut step)? {
+        Some(()) => Err("the argument of a host function does not reach a normal form".to_string()),
+        None => Ok(()),
+    }
+}
+
+// Every node connected to the other side of `arg` without crossing `arg.node`
+fn reachable(net: &Net, arg: Port) -> HashSet<NodeId> {
+    let mut seen = HashSet::from([arg.node]);
+    let mut stack = Vec::new();
+    let start = net.port(arg.node, arg.slot);
+    if !start.is_null() && seen.insert(start.node) {
+        stack.push(start.node);
+    }
+    while let Some(id) = stack.pop() {
+        for slot in 0..net.get_node(id).arity() {
+            let peer = net.port(id, slot);
+            if !peer.is_null() && seen.insert(peer.node) {
+                stack.push(peer.node);
+            }
+        }
+    }
+    seen.remove(&arg.node);
+    seen
+}
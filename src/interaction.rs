@@ -1,5 +1,9 @@
 // || shree ganesh ||
-use crate::net::{Net, Node, NodeId, Port, Redex};
+use crate::host;
+use crate::net::{Net, Node, NodeId, NodeKind, Port, Redex};
+use crate::term::LAMBDA_TAG;
+use std::fmt;
+use std::str::FromStr;
 
 //main function of this is to set rules for the redexes
 // as without the interaction the redex will be just be a static data structure
@@ -25,11 +29,152 @@ impl std::fmt::Display for InteractionResult {
     }
 }
 
+// The rewrite rule two agents trigger when they meet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rule {
+    Annihilate, // same agent: the two nodes vanish and their wires are joined
+    Commute,    // different agents: each one is copied through the other
+    Erase,      // an eraser consumes a node and spreads to its auxiliary wires
+    Void,       // two erasers cancel out
+    Expand,     // a reference is replaced by a copy of its definition
+}
+
+impl Rule {
+    pub fn between(a: &NodeKind, b: &NodeKind) -> Option<Rule> {
+        match (a, b) {
+            (NodeKind::Ref(_), _) | (_, NodeKind::Ref(_)) => Some(Rule::Expand),
+            (NodeKind::Con(x), NodeKind::Con(y)) if x == y => Some(Rule::Annihilate),
+            (NodeKind::Dup(x), NodeKind::Dup(y)) if x == y => Some(Rule::Annihilate),
+            (NodeKind::Con(_) | NodeKind::Dup(_), NodeKind::Con(_) | NodeKind::Dup(_)) => {
+                Some(Rule::Commute)
+            }
+            (NodeKind::Era, NodeKind::Era) => Some(Rule::Void),
+            (NodeKind::Era, NodeKind::Con(_) | NodeKind::Dup(_))
+            | (NodeKind::Con(_) | NodeKind::Dup(_), NodeKind::Era) => Some(Rule::Erase),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Rule::Annihilate => "annihilate",
+            Rule::Commute => "commute",
+            Rule::Erase => "erase",
+            Rule::Void => "void",
+            Rule::Expand => "expand",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "annihilate" => Ok(Rule::Annihilate),
+            "commute" => Ok(Rule::Commute),
+            "erase" => Ok(Rule::Erase),
+            "void" => Ok(Rule::Void),
+            "expand" => Ok(Rule::Expand),
+            _ => Err(format!("Unknown rule: {}", s)),
+        }
+    }
+}
+
+// What the rules need from a net. `Net` implements it directly; the parallel
+// runtime implements it over the handful of nodes a worker has locked.
+pub trait NetOps {
+    fn get_node(&self, id: NodeId) -> &Node;
+    fn port(&self, id: NodeId, slot: usize) -> Port;
+    fn connect(&mut self, a: NodeId, a_slot: usize, b: NodeId, b_slot: usize);
+    fn disconnect(&mut self, id: NodeId, slot: usize);
+    fn create_node(&mut self, node: Node) -> NodeId;
+    fn free_node(&mut self, id: NodeId);
+    // Copies the body of a definition into the net and returns its root
+    fn instantiate(&mut self, name: &str) -> Option<NodeId>;
+
+    // Whether references to `name` call a host function (see host.rs)
+    fn is_host(&self, _name: &str) -> bool {
+        false
+    }
+
+    // Calls the host function on the value plugged into `arg` and returns
+    // the port carrying its answer, not plugged into anything yet
+    fn call_host(&mut self, name: &str, _arg: Port) -> Result<Port, String> {
+        Err(self.undefined(name))
+    }
+
+    // The error for a reference to `name` that has nothing behind it
+    fn undefined(&self, name: &str) -> String {
+        format!("Undefined function: {}", name)
+    }
+}
+
+impl NetOps for Net {
+    fn get_node(&self, id: NodeId) -> &Node {
+        Net::get_node(self, id)
+    }
+
+    fn port(&self, id: NodeId, slot: usize) -> Port {
+        Net::port(self, id, slot)
+    }
+
+    fn connect(&mut self, a: NodeId, a_slot: usize, b: NodeId, b_slot: usize) {
+        Net::connect(self, a, a_slot, b, b_slot)
+    }
+
+    fn disconnect(&mut self, id: NodeId, slot: usize) {
+        Net::disconnect(self, id, slot)
+    }
+
+    fn create_node(&mut self, node: Node) -> NodeId {
+        Net::create_node(self, node)
+    }
+
+    fn free_node(&mut self, id: NodeId) {
+        Net::free_node(self, id)
+    }
+
+    fn instantiate(&mut self, name: &str) -> Option<NodeId> {
+        let def_root = self.get_definition(name)?;
+        Some(self.clone_subgraph(def_root))
+    }
+
+    fn is_host(&self, name: &str) -> bool {
+        self.host(name).is_some()
+    }
+
+    fn call_host(&mut self, name: &str, arg: Port) -> Result<Port, String> {
+        let host = self
+            .host(name)
+            .cloned()
+            .ok_or_else(|| Net::undefined(self, name))?;
+        host::call(
+            self,
+            name,
+            &host,
+            arg,
+            |net, name| net.is_host(name),
+            apply_interaction_result,
+        )
+    }
+
+    fn undefined(&self, name: &str) -> String {
+        Net::undefined(self, name)
+    }
+}
+
 //The workflow of this code:
 // Given a redex (pair of node IDs)
 // apply the correct interaction rule
 // this will mutate the net in place
-pub fn apply_interaction(net: &mut Net, redex: Redex) -> InteractionResult {
+//
+// A redex always joins the two principal ports (slot 0), so the rules only
+// have to decide what happens to the wires hanging off the auxiliary ports.
+pub fn apply_interaction<N: NetOps>(net: &mut N, redex: Redex) -> InteractionResult {
     let a = redex.a;
     let b = redex.b;
 
@@ -40,215 +185,281 @@ pub fn apply_interaction(net: &mut Net, redex: Redex) -> InteractionResult {
     //patern matching
     match (node_a, node_b) {
         // 1. Annihilation: Two identical Constructor nodes
-        (
-            Node::Con {
-                tag: tag_a,
-                ports: ports_a,
-            },
-            Node::Con {
-                tag: tag_b,
-                ports: ports_b,
-            },
-        ) if tag_a == tag_b => {
-            net.connect(
-                ports_a[0].node,
-                ports_a[0].slot,
-                ports_b[0].node,
-                ports_b[0].slot,
-            );
-            net.connect(
-                ports_a[1].node,
-                ports_a[1].slot,
-                ports_b[1].node,
-                ports_b[1].slot,
-            );
-
+        (Node::Con { tag: tag_a, .. }, Node::Con { tag: tag_b, .. }) if tag_a == tag_b => {
+            annihilate(net, a, b);
             InteractionResult::Success
         }
-        // 2. Commutation: Constructor meets Duplicator
-        (
-            Node::Con {
-                tag,
-                ports: [a0, a1],
-            },
-            Node::Dup { ports: [b0, b1] },
-        ) => {
-            let dup_l = net.create_node(Node::Dup {
-                ports: [Port::null(), Port::null()],
-            });
-            let dup_r = net.create_node(Node::Dup {
-                ports: [Port::null(), Port::null()],
-            });
-            let con_l = net.create_node(Node::Con {
-                tag,
-                ports: [Port::null(), Port::null()],
-            });
-            let con_r = net.create_node(Node::Con {
-                tag,
-                ports: [Port::null(), Port::null()],
-            });
-
-            net.connect(a0.node, a0.slot, dup_l, 0);
-            net.connect(a1.node, a1.slot, dup_r, 0);
-
-            net.connect(dup_l, 1, con_l, 0);
-            net.connect(dup_r, 1, con_r, 1);
-
-            net.connect(con_l, 1, con_r, 0);
-
-            net.connect(b0.node, b0.slot, con_l, 1);
-            net.connect(b1.node, b1.slot, con_r, 1);
 
+        // 2. Commutation: Constructors of different tags pass through each other,
+        // each tag acting as an independent agent family
+        (Node::Con { .. }, Node::Con { .. }) => {
+            commute(net, a, b);
             InteractionResult::Success
         }
 
-        (
-            Node::Dup { ports: [a0, a1] },
-            Node::Con {
-                tag,
-                ports: [b0, b1],
-            },
-        ) => {
-            let dup_l = net.create_node(Node::Dup {
-                ports: [Port::null(), Port::null()],
-            });
-            let dup_r = net.create_node(Node::Dup {
-                ports: [Port::null(), Port::null()],
-            });
-            let con_l = net.create_node(Node::Con {
-                tag,
-                ports: [Port::null(), Port::null()],
-            });
-            let con_r = net.create_node(Node::Con {
-                tag,
-                ports: [Port::null(), Port::null()],
-            });
-
-            // Connect left and right
-            net.connect(b0.node, b0.slot, dup_l, 0);
-            net.connect(b1.node, b1.slot, dup_r, 0);
-
-            net.connect(dup_l, 1, con_l, 0);
-            net.connect(dup_r, 1, con_r, 1);
-
-            net.connect(con_l, 1, con_r, 0);
-
-            net.connect(a0.node, a0.slot, con_l, 0);
-            net.connect(a1.node, a1.slot, con_r, 1);
-
+        // Commutation: Constructor meets Duplicator (in either order)
+        (Node::Con { .. }, Node::Dup { .. }) | (Node::Dup { .. }, Node::Con { .. }) => {
+            commute(net, a, b);
             InteractionResult::Success
         }
 
         //3. Duplication: Duplicator meets Duplicator
-        (Node::Dup { ports: [a0, a1] }, Node::Dup { ports: [b0, b1] }) => {
-            // Create four new duplicators
-            let d0 = net.create_node(Node::Dup {
-                ports: [Port::null(), Port::null()],
-            });
-            let d1 = net.create_node(Node::Dup {
-                ports: [Port::null(), Port::null()],
-            });
-            let d2 = net.create_node(Node::Dup {
-                ports: [Port::null(), Port::null()],
-            });
-            let d3 = net.create_node(Node::Dup {
-                ports: [Port::null(), Port::null()],
-            });
-
-            //Create new connections
-            net.connect(a0.node, a0.slot, d0, 0);
-            net.connect(a1.node, a1.slot, d1, 0);
-            net.connect(b0.node, b0.slot, d2, 0);
-            net.connect(b1.node, b1.slot, d3, 0);
-
-            net.connect(d0, 1, d2, 1);
-            net.connect(d1, 1, d3, 1);
-
+        // With the same label they were made by the same sharing, so the
+        // copies pair up and annihilate
+        (Node::Dup { label: label_a, .. }, Node::Dup { label: label_b, .. })
+            if label_a == label_b =>
+        {
+            annihilate(net, a, b);
             InteractionResult::Success
         }
 
-        // 5. Erasure: Constructor meets Eraser
-        (
-            Node::Con {
-                ports: [a0, a1], ..
-            },
-            Node::Era { .. },
-        ) => {
-            // Connect both children to new erasers
-            let era_l = net.create_node(Node::Era { port: Port::null() });
-            let era_r = net.create_node(Node::Era { port: Port::null() });
-            net.connect(a0.node, a0.slot, era_l, 0);
-            net.connect(a1.node, a1.slot, era_r, 0);
+        // Otherwise each one copies the other, like constructors of different tags
+        (Node::Dup { .. }, Node::Dup { .. }) => {
+            commute(net, a, b);
             InteractionResult::Success
         }
 
-        // Erasure: Duplicator meets Eraser
-        (Node::Dup { ports: [a0, a1] }, Node::Era { .. }) => {
-            // Connect both children to new erasers
-            let era_l = net.create_node(Node::Era { port: Port::null() });
-            let era_r = net.create_node(Node::Era { port: Port::null() });
-            net.connect(a0.node, a0.slot, era_l, 0);
-            net.connect(a1.node, a1.slot, era_r, 0);
+        // 5. Erasure: Constructor or Duplicator meets Eraser
+        (Node::Con { .. } | Node::Dup { .. }, Node::Era { .. }) => {
+            erase(net, a, b);
             InteractionResult::Success
         }
 
-        // Erasure: Eraser meets Constructor
-        (
-            Node::Era { .. },
-            Node::Con {
-                ports: [b0, b1], ..
-            },
-        ) => {
-            let era_l = net.create_node(Node::Era { port: Port::null() });
-            let era_r = net.create_node(Node::Era { port: Port::null() });
-            net.connect(b0.node, b0.slot, era_l, 0);
-            net.connect(b1.node, b1.slot, era_r, 0);
+        // Erasure: Eraser meets Constructor or Duplicator
+        (Node::Era { .. }, Node::Con { .. } | Node::Dup { .. }) => {
+            erase(net, b, a);
             InteractionResult::Success
         }
 
-        // Erasure: Eraser meets Duplicator
-        (Node::Era { .. }, Node::Dup { ports: [b0, b1] }) => {
-            let era_l = net.create_node(Node::Era { port: Port::null() });
-            let era_r = net.create_node(Node::Era { port: Port::null() });
-            net.connect(b0.node, b0.slot, era_l, 0);
-            net.connect(b1.node, b1.slot, era_r, 0);
+        // Erasure: Eraser meets Eraser (both simply vanish)
+        (Node::Era { .. }, Node::Era { .. }) => {
+            net.free_node(a);
+            net.free_node(b);
             InteractionResult::Success
         }
 
-        // Erasure: Eraser meets Eraser (nothing to do)
-        (Node::Era { .. }, Node::Era { .. }) => InteractionResult::Success,
+        // Host functions are called instead of unfolded
+        (Node::Ref { name, .. }, _) if net.is_host(&name) => call_host(net, a, b, &name),
+        (_, Node::Ref { name, .. }) if net.is_host(&name) => call_host(net, b, a, &name),
 
         //6. Reference node: Instantiate function if possible
         (Node::Ref { name, .. }, _) => {
-            if let Some(def_root) = net.get_definition(&name) {
-                // Clone the function body, connect its root to the other node
-                let clone_root = net.clone_subgraph(def_root);
+            // Clone the function body, connect its root to the other node
+            if let Some(clone_root) = net.instantiate(&name) {
                 // Connect the clone to the other node
                 net.connect(clone_root, 0, b, 0);
+                net.free_node(a);
                 InteractionResult::Success
             } else {
-                InteractionResult::Error(format!("Undefined function: {}", name))
+                InteractionResult::Error(net.undefined(&name))
             }
         }
 
         // Reference node: Instantiate function if possible (symmetric)
         (_, Node::Ref { name, .. }) => {
-            if let Some(def_root) = net.get_definition(&name) {
-                let clone_root = net.clone_subgraph(def_root);
+            if let Some(clone_root) = net.instantiate(&name) {
                 net.connect(clone_root, 0, a, 0);
+                net.free_node(b);
                 InteractionResult::Success
             } else {
-                InteractionResult::Error(format!("Undefined function: {}", name))
+                InteractionResult::Error(net.undefined(&name))
             }
         }
 
-        // Fallback: No rule matches
+        // Fallback: No rule matches (the root never interacts)
         _ => InteractionResult::Error("Unsupported interaction".to_string()),
     }
 }
 
+// Unfolds a reference whose principal port is plugged into anything, not just
+// another principal port. Lazy evaluation needs this when the value it is
+// after is the body of a definition sitting in an argument position.
+pub fn expand_ref<N: NetOps>(net: &mut N, id: NodeId) -> Result<(), String> {
+    let Node::Ref { name, .. } = net.get_node(id).clone() else {
+        return Err(format!("Node {} is not a reference", id));
+    };
+    if net.is_host(&name) {
+        return Err(format!("Host function {} can only be called", name));
+    }
+    let clone_root = net.instantiate(&name).ok_or_else(|| net.undefined(&name))?;
+    let target = net.port(id, 0);
+    net.free_node(id);
+    link(net, Port::new(clone_root, 0), target);
+    Ok(())
+}
+
+// A host function meets the application it is the function of: the
+// argument goes to the host and its answer takes the place of the result
+fn call_host<N: NetOps>(net: &mut N, r: NodeId, app: NodeId, name: &str) -> InteractionResult {
+    if !matches!(net.get_node(app), Node::Con { tag, .. } if *tag == LAMBDA_TAG) {
+        return InteractionResult::Error(format!(
+            "Host function {} has to be applied to an argument",
+            name
+        ));
+    }
+    let answer = match net.call_host(name, Port::new(app, 1)) {
+        Ok(answer) => answer,
+        Err(msg) => return InteractionResult::Error(msg),
+    };
+
+    let arg = net.port(app, 1);
+    if !arg.is_null() {
+        let era = net.create_node(Node::Era { port: Port::null() });
+        link(net, Port::new(era, 0), arg);
+    }
+    let out = net.port(app, 2);
+    if out.is_null() {
+        // Nobody is waiting for the answer
+        let era = net.create_node(Node::Era { port: Port::null() });
+        link(net, Port::new(era, 0), answer);
+    } else {
+        link(net, answer, out);
+    }
+    net.free_node(r);
+    net.free_node(app);
+    InteractionResult::Success
+}
+
+// Builds a fresh, unconnected node of the same kind (and tag) as `node`
+fn fresh_copy(node: &Node) -> Node {
+    let ports = [Port::null(), Port::null(), Port::null()];
+    match node {
+        Node::Con { tag, .. } => Node::Con { tag: *tag, ports },
+        Node::Dup { label, .. } => Node::Dup {
+            label: *label,
+            ports,
+        },
+        Node::Ref { name, .. } => Node::Ref {
+            name: name.clone(),
+            port: Port::null(),
+        },
+        Node::Era { .. } => Node::Era { port: Port::null() },
+        Node::Root { .. } => Node::Root { port: Port::null() },
+    }
+}
+
+// Plugs two wire ends together. A null end means that side of the wire was
+// free, so the surviving end is left free too instead of pointing at a node
+// that has just been consumed.
+fn link<N: NetOps>(net: &mut N, x: Port, y: Port) {
+    match (x.is_null(), y.is_null()) {
+        (false, false) => net.connect(x.node, x.slot, y.node, y.slot),
+        (false, true) => net.disconnect(x.node, x.slot),
+        (true, false) => net.disconnect(y.node, y.slot),
+        (true, true) => {}
+    }
+}
+
+// Moves every wire hanging off an old auxiliary port onto its replacement.
+// A wire that ran between two old ports ends up between their replacements.
+fn rewire<N: NetOps>(net: &mut N, moves: &[(Port, Port)]) {
+    let peers: Vec<Port> = moves
+        .iter()
+        .map(|(old, _)| net.port(old.node, old.slot))
+        .collect();
+
+    for (i, &(_, new)) in moves.iter().enumerate() {
+        match moves.iter().position(|(old, _)| *old == peers[i]) {
+            Some(j) if j > i => link(net, new, moves[j].1),
+            Some(_) => {} // already linked from the other end
+            None => link(net, new, peers[i]),
+        }
+    }
+}
+
+// Annihilation: the i-th auxiliary wire of `a` is joined with the i-th
+// auxiliary wire of `b`. Wires that loop back into the redex are followed
+// until they leave it again; loops that never leave simply disappear.
+fn annihilate<N: NetOps>(net: &mut N, a: NodeId, b: NodeId) {
+    let pairs = [
+        (Port::new(a, 1), Port::new(b, 1)),
+        (Port::new(a, 2), Port::new(b, 2)),
+    ];
+    let consumed: Vec<Port> = pairs.iter().flat_map(|&(x, y)| [x, y]).collect();
+    let partner = |p: Port| -> Port {
+        let (x, y) = pairs
+            .iter()
+            .copied()
+            .find(|&(x, y)| x == p || y == p)
+            .unwrap();
+        if x == p { y } else { x }
+    };
+
+    let mut visited = Vec::new();
+    for &start in &consumed {
+        let outside = net.port(start.node, start.slot);
+        if visited.contains(&start) || consumed.contains(&outside) {
+            continue;
+        }
+
+        // Walk through the redex until we come out on the other side
+        visited.push(start);
+        let mut inner = partner(start);
+        visited.push(inner);
+        let mut other = net.port(inner.node, inner.slot);
+        while consumed.contains(&other) {
+            visited.push(other);
+            inner = partner(other);
+            visited.push(inner);
+            other = net.port(inner.node, inner.slot);
+        }
+
+        link(net, outside, other);
+    }
+
+    net.free_node(a);
+    net.free_node(b);
+}
+
+// Commutation: `a` and `b` pass through each other. Each gets two copies of
+// the other on its auxiliary ports, and the four copies are cross-linked.
+fn commute<N: NetOps>(net: &mut N, a: NodeId, b: NodeId) {
+    let node_a = net.get_node(a).clone();
+    let node_b = net.get_node(b).clone();
+
+    let b1 = net.create_node(fresh_copy(&node_b));
+    let b2 = net.create_node(fresh_copy(&node_b));
+    let a1 = net.create_node(fresh_copy(&node_a));
+    let a2 = net.create_node(fresh_copy(&node_a));
+
+    net.connect(b1, 1, a1, 1);
+    net.connect(b1, 2, a2, 1);
+    net.connect(b2, 1, a1, 2);
+    net.connect(b2, 2, a2, 2);
+
+    rewire(
+        net,
+        &[
+            (Port::new(a, 1), Port::new(b1, 0)),
+            (Port::new(a, 2), Port::new(b2, 0)),
+            (Port::new(b, 1), Port::new(a1, 0)),
+            (Port::new(b, 2), Port::new(a2, 0)),
+        ],
+    );
+
+    net.free_node(a);
+    net.free_node(b);
+}
+
+// Erasure: both auxiliary wires of `node` get a new eraser of their own,
+// and `node` disappears together with the eraser it met
+fn erase<N: NetOps>(net: &mut N, node: NodeId, eraser: NodeId) {
+    let era_l = net.create_node(Node::Era { port: Port::null() });
+    let era_r = net.create_node(Node::Era { port: Port::null() });
+    rewire(
+        net,
+        &[
+            (Port::new(node, 1), Port::new(era_l, 0)),
+            (Port::new(node, 2), Port::new(era_r, 0)),
+        ],
+    );
+
+    net.free_node(node);
+    net.free_node(eraser);
+}
+
 impl InteractionResult {
-    pub fn to_result(self) -> Result<(), String> {
+    pub fn into_result(self) -> Result<(), String> {
         match self {
             InteractionResult::Success => Ok(()),
             InteractionResult::Error(msg) => Err(msg),
@@ -256,6 +467,6 @@ impl InteractionResult {
     }
 }
 
-pub fn apply_interaction_result(net: &mut Net, redex: Redex) -> Result<(), String> {
-    apply_interaction(net, redex).to_result()
+pub fn apply_interaction_result<N: NetOps>(net: &mut N, redex: Redex) -> Result<(), String> {
+    apply_interaction(net, redex).into_result()
 }
@@ -0,0 +1,120 @@
+// || shree ganesh ||
+//
+// The untyped lambda calculus frontend: the terms VICE was made to run,
+// written the usual way and compiled into interaction combinators.
+//
+//   λf x. f (f x)           or   \f x. f (f x)
+//   (λm n f x. m f (n f x)) 2 3
+//
+// The notation is that of .hvm definition bodies (parser.rs), so numbers
+// stand for Church numerals and `let x = v; body` for (λx. body) v. The
+// operators and the prelude names (succ, mul, is_zero, ...) may be used
+// too; their definitions are written out in place, so the result is
+// always a plain closed term, with no references left in it.
+//
+// compile() then builds the net the standard way (see term.rs):
+//
+//   λx. body       a Con node with LAMBDA_TAG, the term on its principal
+//                  port, the variable on aux 1 and the body on aux 2
+//   f a            a Con node with LAMBDA_TAG, f on its principal port,
+//                  a on aux 1 and the result on aux 2
+//   x used twice   the variable goes through a chain of Dup nodes with a
+//                  label of its own (see linear.rs)
+//   x not used     the variable is plugged into an Era
+//
+// and plugs the term into the root, ready for a Runtime. read_value() with
+// Encoding::Church reads numerals and booleans back; readback::read_term()
+// reads back any normal form, with fresh variable names, and pretty()
+// prints it in the notation above:
+//
+//   (λm n. n m) 2 2   reduces to   λa b. a (a (a (a b)))
+//
+// Alike terms print alike, so a result can be checked against any other
+// normalizer by comparing the two strings.
+
+use crate::diagnostic::Diagnostic;
+use crate::net::Net;
+use crate::parser;
+use crate::term::{Term, encode};
+
+pub fn parse(text: &str) -> Result<Term, Vec<Diagnostic>> {
+    parser::parse_term(text)
+}
+
+// The net for a closed term, its value plugged into the root
+pub fn compile(term: &Term) -> Result<Net, String> {
+    let mut net = Net::new();
+    let port = encode(&mut net, term)?;
+    net.set_root(port.node, port.slot);
+    Ok(net)
+}
+
+// How tightly a form binds, as in formatter.rs
+const TERM: u8 = 0; // lambda and dup
+const APPLICATION: u8 = 1;
+const ATOM: u8 = 2;
+
+// The term in the notation parse() reads: binders merged, applications to
+// the left, and no more parentheses than that needs. Constructors, erased
+// values and leftover duplications have no such notation and are written
+// #tag{a b}, * and `dup #label{a b} = value; body`.
+pub fn pretty(term: &Term) -> String {
+    print(term, TERM)
+}
+
+fn print(term: &Term, context: u8) -> String {
+    let (text, precedence) = match term {
+        Term::Var(name) | Term::Ref(name) => (name.clone(), ATOM),
+        Term::Lam(..) => {
+            let mut vars = Vec::new();
+            let mut body = term;
+            while let Term::Lam(var, inner) = body {
+                vars.push(var.as_str());
+                body = inner;
+            }
+            (format!("λ{}. {}", vars.join(" "), print(body, TERM)), TERM)
+        }
+        Term::App(..) => {
+            let mut args = Vec::new();
+            let mut head = term;
+            while let Term::App(fun, arg) = head {
+                args.push(print(arg, ATOM));
+                head = fun;
+            }
+            args.push(print(head, ATOM));
+            args.reverse();
+            (args.join(" "), APPLICATION)
+        }
+        Term::Con(tag, left, right) => (
+            format!("#{}{{{} {}}}", tag, print(left, ATOM), print(right, ATOM)),
+            ATOM,
+        ),
+        Term::Dup(label, a, b, value, body) => (
+            format!(
+                "dup #{}{{{} {}}} = {}; {}",
+                label,
+                a,
+                b,
+                print(value, TERM),
+                print(body, TERM)
+            ),
+            TERM,
+        ),
+        Term::Era => ("*".to_string(), ATOM),
+        Term::Thunk => ("...".to_string(), ATOM),
+    };
+    if precedence < context {
+        format!("({})", text)
+    } else {
+        text
+    }
+}
+
+// λf x. f (f ... x), with n applications of f
+pub fn church(n: u64) -> Term {
+    let mut body = Term::var("x");
+    for _ in 0..n {
+        body = Term::app(Term::var("f"), body);
+    }
+    Term::lam("f", Term::lam("x", body))
+}
@@ -0,0 +1,21 @@
+pub mod analysis;
+pub mod batch;
+pub mod breakpoint;
+pub mod checkpoint;
+pub mod diagnostic;
+pub mod formatter;
+pub mod fuel;
+pub mod host;
+pub mod interaction;
+pub mod lambda;
+pub mod linear;
+pub mod net;
+pub mod observer;
+pub mod parallel;
+pub mod parser;
+pub mod profile;
+pub mod readback;
+pub mod runtime;
+pub mod term;
+pub mod trace;
+pub mod verify;
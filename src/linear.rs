@@ -0,0 +1,199 @@
+// || shree ganesh ||
+//
+// Linearization. A wire joins exactly two ports, so in a net every variable
+// is used once and only once. Lowering a .hvm definition (parser.rs) runs
+// each binder through a Linearizer, which rewrites the ones that break the
+// rule:
+//
+//   λx. x * x      becomes   λx. dup #4{x%1 x%2} = x; x%1 * x%2
+//   λx. x x x      becomes   λx. dup #5{x%1 x} = x; dup #5{x%2 x%3} = x; ...
+//   λt f. t        stays, and f is plugged into an Era when the net is built
+//
+// Every binding site gets a label of its own, from one counter for the
+// whole program. The duplicators copying one variable share its label, so
+// the two halves of a copy that meet again annihilate, while those of
+// different variables commute through each other (interaction.rs). Without
+// labels a copy of a term that copies, like a numeral used twice, can come
+// apart wrongly. A definition that unfolds gets fresh labels for each copy
+// (Net::clone_subgraph), so `twice twice` copies one instance of `twice`
+// with another. Within a single term labels are fixed when compiling,
+// though, so a term that ends up copying a copy of itself, as (λx. x x) 2
+// does, still can. Telling those copies apart takes more than labels, so
+// instead the runtime refuses the result: such a net always ends up with a
+// vicious circle in it (analysis.rs), and run() reports that as an error.
+//
+// Each rewrite is kept as a Sharing: the variable, where it is bound and
+// used, and the label it got. diagnostic() shows one as a note.
+
+use crate::diagnostic::{Diagnostic, Source, Span};
+use crate::term::Term;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sharing {
+    pub definition: String,
+    pub variable: String,
+    pub binder: Span,
+    pub uses: Vec<Span>,
+    pub label: Option<u32>, // None when the variable is erased instead
+    source: Arc<Source>,
+}
+
+impl Sharing {
+    pub fn diagnostic(&self) -> Diagnostic {
+        let message = match self.label {
+            Some(label) => format!(
+                "`{}` is used {} times in `{}`, so it is copied by duplicators labelled #{}",
+                self.variable,
+                self.uses.len(),
+                self.definition,
+                label
+            ),
+            None => format!(
+                "`{}` is never used in `{}`, so its value is erased",
+                self.variable, self.definition
+            ),
+        };
+        Diagnostic::note(&self.source, self.binder, message)
+    }
+}
+
+#[derive(Debug)]
+pub struct Linearizer {
+    next_label: u32, // 0 is left to hand-built nets
+    pub sharing: Vec<Sharing>,
+}
+
+impl Default for Linearizer {
+    fn default() -> Self {
+        Linearizer {
+            next_label: 1,
+            sharing: Vec::new(),
+        }
+    }
+}
+
+impl Linearizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn fresh_label(&mut self) -> u32 {
+        let label = self.next_label;
+        self.next_label += 1;
+        label
+    }
+
+    // `body`, in which `var` (bound at `binder` in `definition`) is used at
+    // `uses`, rewritten so that it is used once at most
+    pub fn bind(
+        &mut self,
+        definition: &str,
+        source: &Arc<Source>,
+        var: &str,
+        binder: Span,
+        uses: Vec<Span>,
+        body: Term,
+    ) -> Term {
+        if uses.len() == 1 {
+            return body;
+        }
+        let label = (uses.len() > 1).then(|| self.fresh_label());
+        self.sharing.push(Sharing {
+            definition: definition.to_string(),
+            variable: var.to_string(),
+            binder,
+            uses,
+            label,
+            source: source.clone(),
+        });
+        match label {
+            Some(label) => duplicate(var, label, body),
+            None => body,
+        }
+    }
+
+    // A copy of `term` with fresh labels, for a term that is written out in
+    // more than one place
+    pub fn relabel(&mut self, term: &Term) -> Term {
+        let mut labels = HashMap::new();
+        self.relabel_with(term, &mut labels)
+    }
+
+    fn relabel_with(&mut self, term: &Term, labels: &mut HashMap<u32, u32>) -> Term {
+        match term {
+            Term::Dup(label, a, b, value, body) => {
+                let label = *labels.entry(*label).or_insert_with(|| self.fresh_label());
+                Term::Dup(
+                    label,
+                    a.clone(),
+                    b.clone(),
+                    Box::new(self.relabel_with(value, labels)),
+                    Box::new(self.relabel_with(body, labels)),
+                )
+            }
+            Term::Lam(var, body) => {
+                Term::Lam(var.clone(), Box::new(self.relabel_with(body, labels)))
+            }
+            Term::App(fun, arg) => Term::app(
+                self.relabel_with(fun, labels),
+                self.relabel_with(arg, labels),
+            ),
+            Term::Con(tag, left, right) => Term::Con(
+                *tag,
+                Box::new(self.relabel_with(left, labels)),
+                Box::new(self.relabel_with(right, labels)),
+            ),
+            term => term.clone(),
+        }
+    }
+}
+
+// Gives each free use of `var` in `body` a name of its own (x%1, x%2, ...)
+// and puts a chain of duplicators in front that hands them their copies,
+// each one taking `var` and binding it again to what is left to share
+fn duplicate(var: &str, label: u32, mut body: Term) -> Term {
+    let mut names = Vec::new();
+    rename_uses(&mut body, var, &mut names);
+    let Some((last, rest)) = names.split_last() else {
+        return body;
+    };
+    let mut chain: Vec<(String, String)> = Vec::new();
+    for (i, name) in rest.iter().enumerate() {
+        let remainder = if i + 1 == rest.len() {
+            last.clone()
+        } else {
+            var.to_string()
+        };
+        chain.push((name.clone(), remainder));
+    }
+    chain.into_iter().rev().fold(body, |body, (a, b)| {
+        Term::Dup(label, a, b, Box::new(Term::var(var)), Box::new(body))
+    })
+}
+
+fn rename_uses(term: &mut Term, var: &str, names: &mut Vec<String>) {
+    match term {
+        Term::Var(name) if name == var => {
+            *name = format!("{}%{}", var, names.len() + 1);
+            names.push(name.clone());
+        }
+        Term::Lam(bound, body) => {
+            if bound != var {
+                rename_uses(body, var, names);
+            }
+        }
+        Term::Dup(_, a, b, value, body) => {
+            rename_uses(value, var, names);
+            if a != var && b != var {
+                rename_uses(body, var, names);
+            }
+        }
+        Term::App(a, b) | Term::Con(_, a, b) => {
+            rename_uses(a, var, names);
+            rename_uses(b, var, names);
+        }
+        Term::Var(_) | Term::Ref(_) | Term::Era | Term::Thunk => {}
+    }
+}
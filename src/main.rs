@@ -1,31 +1,36 @@
-mod interaction;
-mod net;
-mod runtime;
+use std::process::ExitCode;
+use vice::formatter;
+use vice::net::{Net, Node, Port};
+use vice::runtime::{RunOutcome, Runtime};
 
-use net::{Net, Node, Port};
-use runtime::Runtime;
+fn main() -> ExitCode {
+    // `vice fmt [--check] FILE...` formats .hvm sources instead
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|arg| arg == "fmt") {
+        return fmt(&args[1..]);
+    }
 
-fn main() {
     // Create a simple program that adds two numbers: 2 + 3
     let mut net = Net::new();
 
     // First, create the constructor nodes for the numbers 2 and 3
     let two = net.create_node(Node::Con {
         tag: 2,
-        ports: [Port::null(), Port::null()],
+        ports: [Port::null(), Port::null(), Port::null()],
     });
     let three = net.create_node(Node::Con {
         tag: 3,
-        ports: [Port::null(), Port::null()],
+        ports: [Port::null(), Port::null(), Port::null()],
     });
 
     // Create an "Add" function node (represented as a constructor with tag 100)
     let add = net.create_node(Node::Con {
         tag: 100,
-        ports: [Port::null(), Port::null()],
+        ports: [Port::null(), Port::null(), Port::null()],
     });
 
     // Connect the numbers to the add function
+    // (slot 0 is the principal port, so add and two form an active pair)
     net.connect(add, 0, two, 0);
     net.connect(add, 1, three, 0);
 
@@ -36,14 +41,36 @@ fn main() {
     // Create a runtime and run the computation
     let mut runtime = Runtime::new(net);
 
+    // Ctrl-C stops the evaluation cleanly and reports how far it got
+    let token = runtime.cancel_token();
+    if let Err(e) = ctrlc::set_handler(move || token.cancel()) {
+        println!("Could not install the Ctrl-C handler: {}", e);
+    }
+
     // Option 1: Run to completion
     match runtime.run() {
-        Ok(_) => {
-            println!("\nComputation finished successfully!");
+        Ok(outcome) => {
+            match outcome {
+                RunOutcome::Normal => println!("\nComputation finished successfully!"),
+                RunOutcome::Cancelled => {
+                    println!("\nComputation cancelled, stats so far:");
+                    println!("{:?}", runtime.get_stats());
+                    return ExitCode::SUCCESS;
+                }
+                _ => println!("\nComputation stopped early: {:?}", outcome),
+            }
             println!("Steps taken: {}", runtime.get_stats().steps);
             println!("Maximum nodes: {}", runtime.get_stats().max_nodes);
+            println!(
+                "Wall time: {:?} ({:.2} MIPS)",
+                runtime.get_stats().wall_time,
+                runtime.get_stats().mips()
+            );
             println!("\nFinal result:");
             println!("{:?}", runtime.get_net());
+            for stuck in runtime.get_stuck_terms() {
+                println!("Stuck: {}", stuck);
+            }
         }
         Err(e) => println!("Error during computation: {}", e),
     }
@@ -69,4 +96,51 @@ fn main() {
             }
         }
     }
+    ExitCode::SUCCESS
+}
+
+// Rewrites each file in its canonical layout. With --check nothing is
+// written; the files that would change are listed and the exit code says
+// whether there were any.
+fn fmt(args: &[String]) -> ExitCode {
+    let check = args.iter().any(|arg| arg == "--check");
+    let files: Vec<&String> = args.iter().filter(|arg| *arg != "--check").collect();
+    if files.is_empty() {
+        eprintln!("usage: vice fmt [--check] FILE...");
+        return ExitCode::from(2);
+    }
+
+    let mut failed = false;
+    for file in files {
+        let result = if check {
+            formatter::check_file(file).map(|(formatted, moved)| (formatted.is_some(), moved))
+        } else {
+            formatter::format_file(file)
+        };
+        let result = result.map(|(changed, moved)| {
+            for note in moved {
+                eprint!("{}", note.render());
+            }
+            changed
+        });
+        match result {
+            Ok(true) if check => {
+                println!("{} is not formatted", file);
+                failed = true;
+            }
+            Ok(true) => println!("formatted {}", file),
+            Ok(false) => {}
+            Err(errors) => {
+                for error in errors {
+                    eprint!("{}", error.render());
+                }
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
 }
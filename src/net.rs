@@ -4,7 +4,10 @@
 // building blocks called nodes. These graphs are called interaction nets.
 //
 // Here in this file we are defining the structure of nodes and their properties
-use std::collections::{HashMap, VecDeque};
+use crate::diagnostic::SourceMap;
+use crate::host::HostFn;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
 
 //Using hashmap to store function definition by name
 // Using VecDeque for storing active pairs
@@ -14,7 +17,7 @@ use std::fmt;
 
 pub type NodeId = usize; // this is done to uniquely identify nodes in the network and usize is for dynamically match the pointer size
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 
 //port will be used as a connector between the nodes...
 // Ports let nodes link to each other in a flexible, "plug-and-play" manner.
@@ -33,28 +36,150 @@ impl Port {
     pub fn null() -> Self {
         // MAX means the largest possible number, which we use to mean "not connected" /// need to clarify this part
         Self {
-            node: std::usize::MAX,
+            node: usize::MAX,
             slot: 0,
         }
     }
 
     // checking if the node is not connected to anything
     pub fn is_null(&self) -> bool {
-        self.node == std::usize::MAX
+        self.node == usize::MAX
     }
 }
 
 #[derive(Debug, Clone)]
 
 //here we are defining the type of nodes and their properties
+// Slot 0 of every node is its principal port; two nodes only interact when
+// they are connected principal to principal. Slots 1 and 2 are auxiliary.
 pub enum Node {
-    Con { tag: i32, ports: [Port; 2] }, //Represents data (like numbers, lists, etc). Has a tag and two connections.
-    Dup { ports: [Port; 2] },           //Copies data, enabling sharing. Has two connections.
+    Con { tag: i32, ports: [Port; 3] }, //Represents data (like numbers, lists, etc). Has a tag, a principal and two auxiliary connections.
+    Dup { label: u32, ports: [Port; 3] }, //Copies data, enabling sharing. Has a principal and two auxiliary connections; duplicators only annihilate with the same label.
     Ref { name: String, port: Port }, //Calls a function or references a definition. Has a name and one connection.
     Era { port: Port },               //Deletes or ignores data. Has one connection
+    Root { port: Port }, //Marks where the result of the program comes out. Never interacts.
 }
 
-#[derive(Debug, Clone, Copy)]
+impl Node {
+    // Reads the port plugged into the given slot
+    pub fn port(&self, slot: usize) -> Port {
+        match self {
+            Node::Con { ports, .. } | Node::Dup { ports, .. } if slot < 3 => ports[slot],
+            Node::Ref { port, .. } | Node::Era { port } | Node::Root { port } if slot == 0 => *port,
+            _ => panic!("Invalid port slot for node"),
+        }
+    }
+
+    pub fn set_port(&mut self, slot: usize, target: Port) {
+        match self {
+            Node::Con { ports, .. } | Node::Dup { ports, .. } if slot < 3 => ports[slot] = target,
+            Node::Ref { port, .. } | Node::Era { port } | Node::Root { port } if slot == 0 => {
+                *port = target
+            }
+            _ => panic!("Invalid port slot for node"),
+        }
+    }
+
+    // Gives a duplicator the label `labels` maps its own to, taking a new
+    // one from `fresh` for a label met for the first time. Used on the
+    // copies a definition unfolds into.
+    pub(crate) fn relabel(&mut self, labels: &mut HashMap<u32, u32>, fresh: impl FnOnce() -> u32) {
+        if let Node::Dup { label, .. } = self {
+            *label = *labels.entry(*label).or_insert_with(fresh);
+        }
+    }
+
+    pub fn kind(&self) -> NodeKind {
+        match self {
+            Node::Con { tag, .. } => NodeKind::Con(*tag),
+            Node::Dup { label, .. } => NodeKind::Dup(*label),
+            Node::Ref { name, .. } => NodeKind::Ref(name.clone()),
+            Node::Era { .. } => NodeKind::Era,
+            Node::Root { .. } => NodeKind::Root,
+        }
+    }
+
+    // Number of slots, principal included
+    pub fn arity(&self) -> usize {
+        match self {
+            Node::Con { .. } | Node::Dup { .. } => 3,
+            Node::Ref { .. } | Node::Era { .. } | Node::Root { .. } => 1,
+        }
+    }
+
+    // Whether a rule exists for the two nodes meeting principal to principal.
+    // Constructors of any tag interact with each other: equal tags annihilate,
+    // different tags commute, so every tag behaves as its own agent family.
+    pub fn interacts_with(&self, other: &Node) -> bool {
+        matches!(
+            (self, other),
+            (Node::Con { .. }, Node::Con { .. })
+                | (Node::Con { .. }, Node::Dup { .. })
+                | (Node::Dup { .. }, Node::Con { .. })
+                | (Node::Con { .. }, Node::Era { .. })
+                | (Node::Era { .. }, Node::Con { .. })
+                | (Node::Dup { .. }, Node::Dup { .. })
+                | (Node::Dup { .. }, Node::Era { .. })
+                | (Node::Era { .. }, Node::Dup { .. })
+                | (Node::Era { .. }, Node::Era { .. })
+                | (Node::Ref { .. }, _)
+                | (_, Node::Ref { .. })
+        )
+    }
+}
+
+// What a node is, without its wiring
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum NodeKind {
+    Con(i32),
+    Dup(u32),
+    Ref(String),
+    Era,
+    Root,
+}
+
+impl fmt::Display for NodeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeKind::Con(tag) => write!(f, "CON({})", tag),
+            NodeKind::Dup(label) => write!(f, "DUP({})", label),
+            NodeKind::Ref(name) => write!(f, "REF({})", name),
+            NodeKind::Era => write!(f, "ERA"),
+            NodeKind::Root => write!(f, "ROOT"),
+        }
+    }
+}
+
+// Reads back what Display writes
+impl std::str::FromStr for NodeKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let inner = |prefix: &str| s.strip_prefix(prefix)?.strip_suffix(')');
+        match s {
+            "ERA" => Ok(NodeKind::Era),
+            "ROOT" => Ok(NodeKind::Root),
+            _ => {
+                if let Some(tag) = inner("CON(") {
+                    tag.parse()
+                        .map(NodeKind::Con)
+                        .map_err(|_| format!("Bad constructor tag: {}", s))
+                } else if let Some(label) = inner("DUP(") {
+                    label
+                        .parse()
+                        .map(NodeKind::Dup)
+                        .map_err(|_| format!("Bad duplicator label: {}", s))
+                } else if let Some(name) = inner("REF(") {
+                    Ok(NodeKind::Ref(name.to_string()))
+                } else {
+                    Err(format!("Unknown node kind: {}", s))
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 
 //Redex: reducible expression, one from the lambda calculus
 // This is actually one of the most interesting topic, it is a data structure
@@ -67,27 +192,69 @@ pub struct Redex {
 
 //It is basically the blueprint for a network of nodes and connections,
 // upon which we can build our program.
+#[derive(Clone)]
 pub struct Net {
     nodes: Vec<Node>,
+    live: Vec<bool>,        // live[id] is false once the node has been consumed
+    free_list: Vec<NodeId>, // slots of consumed nodes, reused by create_node
     active_pairs: VecDeque<Redex>, // Removed unnecessary parentheses
     definitions: HashMap<String, NodeId>, // Changed Node to NodeId
+    root: Option<NodeId>,   // the Root node readback starts from
+    hosts: HashMap<String, HostFn>, // names a reference calls into Rust for, see host.rs
+    sources: Option<Arc<SourceMap>>, // where the program it was compiled from refers to what
+    next_label: u32,        // the next fresh Dup label; 0 is left for hand-built duplicators
+}
+
+impl Default for Net {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Net {
     pub fn new() -> Self {
         Self {
             nodes: Vec::new(),
+            live: Vec::new(),
+            free_list: Vec::new(),
             active_pairs: VecDeque::new(),
             definitions: HashMap::new(),
+            root: None,
+            hosts: HashMap::new(),
+            sources: None,
+            next_label: 1,
         }
     }
 
     pub fn create_node(&mut self, node: Node) -> NodeId {
+        if let Some(id) = self.free_list.pop() {
+            self.nodes[id] = node;
+            self.live[id] = true;
+            return id;
+        }
         let id = self.nodes.len();
         self.nodes.push(node);
+        self.live.push(true);
         id
     }
 
+    // Releases a node consumed by an interaction so its slot can be reused
+    pub fn free_node(&mut self, id: NodeId) {
+        if self.live[id] {
+            self.live[id] = false;
+            self.free_list.push(id);
+        }
+    }
+
+    pub fn is_live(&self, id: NodeId) -> bool {
+        self.live.get(id).copied().unwrap_or(false)
+    }
+
+    // Ids of every node that has not been consumed yet
+    pub fn live_nodes(&self) -> impl Iterator<Item = NodeId> + '_ {
+        (0..self.nodes.len()).filter(|&id| self.live[id])
+    }
+
     pub fn get_node(&self, id: NodeId) -> &Node {
         &self.nodes[id]
     }
@@ -97,90 +264,276 @@ impl Net {
     }
 
     pub fn connect(&mut self, a: NodeId, a_slot: usize, b: NodeId, b_slot: usize) {
-        match &mut self.nodes[a] {
-            // Fixed 'node' to 'nodes'
-            Node::Con { ports, .. } if a_slot < 2 => {
-                ports[a_slot] = Port::new(b, b_slot);
-            }
-            Node::Dup { ports, .. } if a_slot < 2 => {
-                ports[a_slot] = Port::new(b, b_slot);
-            }
-
-            Node::Ref { port, .. } if a_slot == 0 => {
-                // Fixed 'ports' to 'port'
-                *port = Port::new(b, b_slot);
-            }
-            Node::Era { port, .. } if a_slot == 0 => {
-                *port = Port::new(b, b_slot);
-            }
-
-            _ => panic!("Invalid port slot for node"),
-        }
-
+        self.nodes[a].set_port(a_slot, Port::new(b, b_slot));
         // Update the b node as well
-        match &mut self.nodes[b] {
-            Node::Con { ports, .. } if b_slot < 2 => {
-                ports[b_slot] = Port::new(a, a_slot);
-            }
-            Node::Dup { ports, .. } if b_slot < 2 => {
-                ports[b_slot] = Port::new(a, a_slot);
-            }
-            Node::Ref { port, .. } if b_slot == 0 => {
-                *port = Port::new(a, a_slot);
-            }
-            Node::Era { port, .. } if b_slot == 0 => {
-                *port = Port::new(a, a_slot);
-            }
-            _ => panic!("Invalid port slot for node"),
-        }
+        self.nodes[b].set_port(b_slot, Port::new(a, a_slot));
 
-        if self.is_redex(a, b) {
+        if a_slot == 0 && b_slot == 0 && self.is_redex(a, b) {
             self.active_pairs.push_back(Redex { a, b });
         }
     }
 
-    fn is_redex(&self, a: NodeId, b: NodeId) -> bool {
-        match (&self.nodes[a], &self.nodes[b]) {
-            (Node::Con { tag: tag_a, .. }, Node::Con { tag: tag_b, .. }) => tag_a == tag_b,
-            (Node::Con { .. }, Node::Dup { .. }) | (Node::Dup { .. }, Node::Con { .. }) => true,
-            (Node::Con { .. }, Node::Era { .. }) | (Node::Era { .. }, Node::Con { .. }) => true,
+    // Reads the port plugged into the given slot of a node
+    pub fn port(&self, id: NodeId, slot: usize) -> Port {
+        self.nodes[id].port(slot)
+    }
 
-            (Node::Dup { .. }, Node::Dup { .. }) => true,
+    // Unplugs a single slot, leaving it as a free (null) port.
+    // Used when the wire on the other side has been consumed by an interaction.
+    pub fn disconnect(&mut self, id: NodeId, slot: usize) {
+        self.nodes[id].set_port(slot, Port::null());
+    }
 
-            (Node::Dup { .. }, Node::Era { .. }) | (Node::Era { .. }, Node::Dup { .. }) => true,
-            (Node::Ref { .. }, _) | (_, Node::Ref { .. }) => true,
-            _ => false,
-        }
+    // Whether a rule exists for the two nodes meeting principal to principal.
+    pub fn is_redex(&self, a: NodeId, b: NodeId) -> bool {
+        self.nodes[a].interacts_with(&self.nodes[b])
+    }
+
+    // Plugs a Root node into the given port. Whatever ends up on the other side
+    // of that wire once evaluation is over is the result of the program.
+    pub fn set_root(&mut self, node: NodeId, slot: usize) -> NodeId {
+        let root = self.create_node(Node::Root { port: Port::null() });
+        self.connect(root, 0, node, slot);
+        self.root = Some(root);
+        root
+    }
+
+    pub fn root(&self) -> Option<NodeId> {
+        self.root
     }
 
+    // The body hanging off `root` becomes a template: it is only ever copied,
+    // so the redexes inside it come out of the queue instead of firing in place
     pub fn add_definition(&mut self, name: &str, root: NodeId) {
         self.definitions.insert(name.to_string(), root);
+        let body = self.reachable(root);
+        self.active_pairs
+            .retain(|r| !(body.contains(&r.a) && body.contains(&r.b)));
+    }
+
+    // Nodes of every definition body
+    pub fn template_nodes(&self) -> HashSet<NodeId> {
+        self.definitions
+            .values()
+            .flat_map(|&root| self.reachable(root))
+            .collect()
+    }
+
+    fn reachable(&self, root: NodeId) -> HashSet<NodeId> {
+        let mut seen = HashSet::from([root]);
+        let mut stack = vec![root];
+        while let Some(id) = stack.pop() {
+            for slot in 0..self.nodes[id].arity() {
+                let peer = self.port(id, slot);
+                if !peer.is_null() && seen.insert(peer.node) {
+                    stack.push(peer.node);
+                }
+            }
+        }
+        seen
     }
 
     pub fn get_definition(&self, name: &str) -> Option<NodeId> {
         self.definitions.get(name).copied()
     }
 
+    pub fn definitions(&self) -> impl Iterator<Item = (&str, NodeId)> {
+        self.definitions
+            .iter()
+            .map(|(name, &root)| (name.as_str(), root))
+    }
+
+    // Makes references to `name` call `host` instead of unfolding a
+    // definition. The name is reserved: it cannot also be a definition.
+    pub fn register_host(&mut self, name: &str, host: HostFn) -> Result<(), String> {
+        if self.definitions.contains_key(name) {
+            return Err(format!("{} is already a definition", name));
+        }
+        self.hosts.insert(name.to_string(), host);
+        Ok(())
+    }
+
+    pub fn host(&self, name: &str) -> Option<&HostFn> {
+        self.hosts.get(name)
+    }
+
+    pub fn hosts(&self) -> impl Iterator<Item = (&str, &HostFn)> {
+        self.hosts.iter().map(|(name, host)| (name.as_str(), host))
+    }
+
+    pub fn has_hosts(&self) -> bool {
+        !self.hosts.is_empty()
+    }
+
+    pub fn set_source_map(&mut self, sources: SourceMap) {
+        self.sources = Some(Arc::new(sources));
+    }
+
+    pub fn source_map(&self) -> Option<&SourceMap> {
+        self.sources.as_deref()
+    }
+
+    // The error for a reference to `name` with nothing behind it, pointing
+    // into the source when the net was compiled from one
+    pub fn undefined(&self, name: &str) -> String {
+        match self
+            .source_map()
+            .and_then(|sources| sources.undefined(name))
+        {
+            Some(diagnostic) => diagnostic.to_string(),
+            None => format!("Undefined function: {}", name),
+        }
+    }
+
+    // A Dup label no node of this net has had so far
+    pub fn fresh_label(&mut self) -> u32 {
+        let label = self.next_label;
+        self.next_label += 1;
+        label
+    }
+
+    // Keeps fresh_label() from handing out `label`, which is in use
+    pub fn claim_label(&mut self, label: u32) -> Result<(), String> {
+        let next = label
+            .checked_add(1)
+            .ok_or_else(|| format!("Duplicator label {} is out of range", label))?;
+        self.next_label = self.next_label.max(next);
+        Ok(())
+    }
+
+    // The label fresh_label() would hand out next
+    pub(crate) fn next_label(&self) -> u32 {
+        self.next_label
+    }
+
     pub fn next_redex(&mut self) -> Option<Redex> {
         self.active_pairs.pop_front()
     }
 
+    // Pulls a particular pair out of the queue, for evaluators that pick
+    // redexes themselves instead of taking them in order
+    pub fn take_redex(&mut self, a: NodeId, b: NodeId) -> Option<Redex> {
+        let index = self
+            .active_pairs
+            .iter()
+            .position(|r| (r.a == a && r.b == b) || (r.a == b && r.b == a))?;
+        self.active_pairs.remove(index)
+    }
+
+    // Empties the queue, for evaluators that work through part of it on
+    // their own (host.rs) and hand back the rest with queue_redexes()
+    pub(crate) fn take_redexes(&mut self) -> VecDeque<Redex> {
+        std::mem::take(&mut self.active_pairs)
+    }
+
+    pub(crate) fn queue_redexes(&mut self, redexes: impl IntoIterator<Item = Redex>) {
+        self.active_pairs.extend(redexes);
+    }
+
+    // Number of live nodes (consumed nodes are not counted)
     pub fn node_count(&self) -> usize {
-        self.nodes.len()
+        self.nodes.len() - self.free_list.len()
     }
 
     pub fn redex_count(&self) -> usize {
         self.active_pairs.len()
     }
 
-    // Same as before, but updating the clone_node method
+    // The redexes still waiting in the queue, in the order they will fire
+    pub fn active_pairs(&self) -> impl Iterator<Item = &Redex> {
+        self.active_pairs.iter()
+    }
+
+    // Moves the nodes and pending redexes out, for evaluators that keep their
+    // own representation (the parallel runtime). Consumed slots come out as None.
+    // The definitions stay behind, so `import` must be called to put them back.
+    pub(crate) fn export(&mut self) -> (Vec<Option<Node>>, Vec<Redex>) {
+        let nodes = std::mem::take(&mut self.nodes)
+            .into_iter()
+            .zip(std::mem::take(&mut self.live))
+            .map(|(node, live)| if live { Some(node) } else { None })
+            .collect();
+        self.free_list.clear();
+        (nodes, self.active_pairs.drain(..).collect())
+    }
+
+    pub(crate) fn import(
+        &mut self,
+        nodes: Vec<Option<Node>>,
+        active_pairs: Vec<Redex>,
+    ) -> Result<(), String> {
+        self.nodes.clear();
+        self.live.clear();
+        self.free_list.clear();
+        for (id, node) in nodes.into_iter().enumerate() {
+            match node {
+                Some(node) => {
+                    if let Node::Dup { label, .. } = node {
+                        self.claim_label(label)?;
+                    }
+                    self.nodes.push(node);
+                    self.live.push(true);
+                }
+                None => {
+                    self.nodes.push(Node::Era { port: Port::null() });
+                    self.live.push(false);
+                    self.free_list.push(id);
+                }
+            }
+        }
+        self.active_pairs = active_pairs.into();
+        Ok(())
+    }
+
+    // Slots ever allocated, consumed ones included
+    pub(crate) fn slot_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    // Consumed slots in the order create_node hands them out again (last first).
+    // A checkpoint has to keep this order for the resumed run to allocate the
+    // same ids.
+    pub(crate) fn free_list(&self) -> &[NodeId] {
+        &self.free_list
+    }
+
+    pub(crate) fn set_free_list(&mut self, free_list: Vec<NodeId>) {
+        self.free_list = free_list;
+    }
+
+    pub(crate) fn restore_root(&mut self, root: Option<NodeId>) {
+        self.root = root;
+    }
 
+    // Puts back the label counter of a saved net, which may be ahead of
+    // every label still in it but never behind one
+    pub(crate) fn restore_next_label(&mut self, next: u32) -> Result<(), String> {
+        if next < self.next_label {
+            return Err(format!(
+                "next label {} is not past label {} of a live duplicator",
+                next,
+                self.next_label - 1
+            ));
+        }
+        self.next_label = next;
+        Ok(())
+    }
+
+    // Copies the body of a definition, for a reference that unfolds. The
+    // duplicators of the copy get fresh labels, one for each label in the
+    // body, so two unfoldings of one definition never take each other's
+    // duplicators for their own.
     pub fn clone_subgraph(&mut self, root: NodeId) -> NodeId {
         let mut cloned_nodes = HashMap::new();
-        self.clone_node(root, &mut cloned_nodes)
+        self.clone_node(root, &mut cloned_nodes, &mut HashMap::new())
     }
 
-    fn clone_node(&mut self, id: NodeId, cloned_nodes: &mut HashMap<NodeId, NodeId>) -> NodeId {
+    fn clone_node(
+        &mut self,
+        id: NodeId,
+        cloned_nodes: &mut HashMap<NodeId, NodeId>,
+        labels: &mut HashMap<u32, u32>,
+    ) -> NodeId {
         // Check if we've already cloned this node
         if let Some(&cloned_id) = cloned_nodes.get(&id) {
             return cloned_id;
@@ -193,14 +546,17 @@ impl Net {
                 Node::Con { tag, .. } => {
                     let new_node = Node::Con {
                         tag: *tag,
-                        ports: [Port::null(), Port::null()],
+                        ports: [Port::null(), Port::null(), Port::null()],
                     };
                     self.create_node(new_node)
                 }
-                Node::Dup { .. } => {
-                    let new_node = Node::Dup {
-                        ports: [Port::null(), Port::null()],
+                Node::Dup { label, .. } => {
+                    let label = *label;
+                    let mut new_node = Node::Dup {
+                        label,
+                        ports: [Port::null(), Port::null(), Port::null()],
                     };
+                    new_node.relabel(labels, || self.fresh_label());
                     self.create_node(new_node)
                 }
                 Node::Ref { name, .. } => {
@@ -214,6 +570,10 @@ impl Net {
                     let new_node = Node::Era { port: Port::null() };
                     self.create_node(new_node)
                 }
+                Node::Root { .. } => {
+                    let new_node = Node::Root { port: Port::null() };
+                    self.create_node(new_node)
+                }
             }
         };
 
@@ -246,7 +606,7 @@ impl Net {
                         connections_to_clone.push((0, port.node, port.slot));
                     }
                 }
-                Node::Era { port, .. } => {
+                Node::Era { port, .. } | Node::Root { port } => {
                     if !port.is_null() {
                         connections_to_clone.push((0, port.node, port.slot));
                     }
@@ -258,8 +618,11 @@ impl Net {
 
         // Now process the connections
         for (i, connected_id, connected_slot) in connections {
-            let cloned_connected = self.clone_node(connected_id, cloned_nodes);
-            self.connect(new_id, i, cloned_connected, connected_slot);
+            let cloned_connected = self.clone_node(connected_id, cloned_nodes, labels);
+            // The recursive call may already have wired this slot from the other end
+            if self.port(new_id, i).is_null() {
+                self.connect(new_id, i, cloned_connected, connected_slot);
+            }
         }
 
         new_id
@@ -277,17 +640,20 @@ impl Net {
         match &self.nodes[id] {
             Node::Con { tag, ports } => {
                 format!(
-                    "CON({})[{}, {}]",
+                    "CON({})[{}, {}, {}]",
                     tag,
                     self.port_str(&ports[0]),
-                    self.port_str(&ports[1])
+                    self.port_str(&ports[1]),
+                    self.port_str(&ports[2])
                 )
             }
-            Node::Dup { ports } => {
+            Node::Dup { label, ports } => {
                 format!(
-                    "DUP[{}, {}]",
+                    "DUP({})[{}, {}, {}]",
+                    label,
                     self.port_str(&ports[0]),
-                    self.port_str(&ports[1])
+                    self.port_str(&ports[1]),
+                    self.port_str(&ports[2])
                 )
             }
             Node::Ref { name, port } => {
@@ -296,6 +662,9 @@ impl Net {
             Node::Era { port } => {
                 format!("ERA:{}", self.port_str(port))
             }
+            Node::Root { port } => {
+                format!("ROOT:{}", self.port_str(port))
+            }
         }
     }
 }
@@ -303,11 +672,11 @@ impl Net {
 impl fmt::Debug for Net {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // Write the header
-        writeln!(f, "Net with {} nodes:", self.nodes.len())?;
+        writeln!(f, "Net with {} nodes:", self.node_count())?;
 
-        // Write each node
-        for (i, node) in self.nodes.iter().enumerate() {
-            writeln!(f, "  {}: {:?}", i, node)?;
+        // Write each live node
+        for i in self.live_nodes() {
+            writeln!(f, "  {}: {:?}", i, self.nodes[i])?;
         }
 
         // Write function definitions
@@ -0,0 +1,130 @@
+// || shree ganesh ||
+//
+// Observers are the dashboard gauges of the engine. Anything that wants to
+// watch the reduction (a visualizer, a tracer, a profiler, a debugger) plugs
+// in here and gets told about every interaction the runtime applies, instead
+// of driving the step loop by hand.
+//
+// The runtime calls before_interaction() with the net still untouched, then
+// applies the rule, then calls after_interaction() with what the rule did.
+
+use crate::interaction::NetOps;
+use crate::net::{Net, Node, NodeId, NodeKind, Port, Redex};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+pub trait RuntimeObserver {
+    fn before_interaction(&mut self, _net: &Net, _redex: Redex, _kinds: &[NodeKind; 2]) {}
+    fn after_interaction(&mut self, _net: &Net, _event: &InteractionEvent) {}
+}
+
+// An observer shared through an Arc<Mutex<_>> stays readable by its owner
+// while the runtime holds the other handle
+impl<T: RuntimeObserver> RuntimeObserver for Arc<Mutex<T>> {
+    fn before_interaction(&mut self, net: &Net, redex: Redex, kinds: &[NodeKind; 2]) {
+        self.lock().unwrap().before_interaction(net, redex, kinds)
+    }
+
+    fn after_interaction(&mut self, net: &Net, event: &InteractionEvent) {
+        self.lock().unwrap().after_interaction(net, event)
+    }
+}
+
+// What one interaction did to the net
+#[derive(Debug, Clone)]
+pub struct InteractionEvent {
+    pub redex: Redex,
+    pub kinds: [NodeKind; 2],     // the two nodes that met, in redex order
+    pub created: Vec<NodeId>,     // new nodes, copies of an unfolded definition included
+    pub freed: Vec<NodeId>,       // consumed nodes (their ids may already be reused by created)
+    pub wires: Vec<(Port, Port)>, // wires the rule plugged, not the ones inside a copied definition
+}
+
+// Applies a rule through this instead of the bare net to find out what it changed
+pub(crate) struct Recorder<'a> {
+    pub net: &'a mut Net,
+    pub created: Vec<NodeId>,
+    pub freed: Vec<NodeId>,
+    pub wires: Vec<(Port, Port)>,
+}
+
+impl<'a> Recorder<'a> {
+    pub fn new(net: &'a mut Net) -> Self {
+        Recorder {
+            net,
+            created: Vec::new(),
+            freed: Vec::new(),
+            wires: Vec::new(),
+        }
+    }
+}
+
+impl NetOps for Recorder<'_> {
+    fn get_node(&self, id: NodeId) -> &Node {
+        self.net.get_node(id)
+    }
+
+    fn port(&self, id: NodeId, slot: usize) -> Port {
+        self.net.port(id, slot)
+    }
+
+    fn connect(&mut self, a: NodeId, a_slot: usize, b: NodeId, b_slot: usize) {
+        self.wires
+            .push((Port::new(a, a_slot), Port::new(b, b_slot)));
+        self.net.connect(a, a_slot, b, b_slot)
+    }
+
+    fn disconnect(&mut self, id: NodeId, slot: usize) {
+        self.net.disconnect(id, slot)
+    }
+
+    fn create_node(&mut self, node: Node) -> NodeId {
+        let id = self.net.create_node(node);
+        self.created.push(id);
+        id
+    }
+
+    fn free_node(&mut self, id: NodeId) {
+        self.freed.push(id);
+        self.net.free_node(id)
+    }
+
+    fn instantiate(&mut self, name: &str) -> Option<NodeId> {
+        let root = self.net.instantiate(name)?;
+        self.record_copy(root);
+        Some(root)
+    }
+
+    fn is_host(&self, name: &str) -> bool {
+        self.net.is_host(name)
+    }
+
+    fn call_host(&mut self, name: &str, arg: Port) -> Result<Port, String> {
+        let answer = self.net.call_host(name, arg)?;
+        self.record_copy(answer.node);
+        Ok(answer)
+    }
+    fn undefined(&self, name: &str) -> String {
+        self.net.undefined(name)
+    }
+}
+
+impl Recorder<'_> {
+    // Records the nodes of a fresh copy (a definition body or the answer of a
+    // host function) as created. The copy is not plugged into anything yet,
+    // so everything reachable from its root belongs to it.
+    fn record_copy(&mut self, root: NodeId) {
+        let mut copied = HashSet::from([root]);
+        let mut stack = vec![root];
+        self.created.push(root);
+        while let Some(id) = stack.pop() {
+            for slot in 0..self.net.get_node(id).arity() {
+                let peer = self.net.port(id, slot);
+                if !peer.is_null() && copied.insert(peer.node) {
+                    self.created.push(peer.node);
+                    stack.push(peer.node);
+                }
+            }
+        }
+    }
+}
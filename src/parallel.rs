@@ -0,0 +1,589 @@
+// || shree ganesh ||
+//
+// The parallel runtime: several worker threads rewrite disjoint redexes of the
+// same net at the same time.
+//
+// Every worker owns a queue of redexes. It works on the newest entry of its own
+// queue and, when that runs dry, steals the oldest entry of somebody else's.
+// The nodes live in an arena of individually locked slots. To fire a redex a
+// worker locks the two redex nodes together with every node on the other end of
+// their auxiliary wires (the only nodes a rule ever writes to). Locks are taken
+// with try_lock, and a worker that misses one lets go of everything and retries,
+// so two workers can never wait on each other. The rules themselves are the ones
+// in interaction.rs, applied through a view over the locked nodes.
+
+use crate::interaction::{NetOps, apply_interaction_result as apply_interaction};
+use crate::net::{Net, Node, NodeId, Port, Redex};
+use crate::runtime::{CancelToken, RunLimits, RunOutcome};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, OnceLock, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The arena grows in chunks that double in size, so nodes never move once allocated
+const FIRST_CHUNK: usize = 1 << 12;
+const CHUNKS: usize = 40;
+
+type Slot = Mutex<Option<Node>>; // None once the node has been consumed
+
+struct Arena {
+    chunks: Vec<OnceLock<Box<[Slot]>>>,
+    next: AtomicUsize, // first id that has never been handed out
+}
+
+impl Arena {
+    fn new(nodes: Vec<Option<Node>>) -> Self {
+        let arena = Self {
+            chunks: (0..CHUNKS).map(|_| OnceLock::new()).collect(),
+            next: AtomicUsize::new(nodes.len()),
+        };
+        for (id, node) in nodes.into_iter().enumerate() {
+            *arena.slot(id).lock().unwrap() = node;
+        }
+        arena
+    }
+
+    // Chunk c holds FIRST_CHUNK << c slots and starts at FIRST_CHUNK * (2^c - 1)
+    fn locate(id: NodeId) -> (usize, usize) {
+        let x = id / FIRST_CHUNK + 1;
+        let chunk = (usize::BITS - 1 - x.leading_zeros()) as usize;
+        (chunk, id - FIRST_CHUNK * ((1 << chunk) - 1))
+    }
+
+    fn slot(&self, id: NodeId) -> &Slot {
+        let (chunk, offset) = Self::locate(id);
+        let slots = self.chunks[chunk].get_or_init(|| {
+            (0..FIRST_CHUNK << chunk)
+                .map(|_| Mutex::new(None))
+                .collect()
+        });
+        &slots[offset]
+    }
+
+    // Reuses a slot the worker freed earlier, or takes a brand new one
+    fn alloc(&self, free: &mut Vec<NodeId>) -> NodeId {
+        free.pop()
+            .unwrap_or_else(|| self.next.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn into_nodes(self) -> Vec<Option<Node>> {
+        let len = self.next.load(Ordering::Relaxed);
+        let mut nodes = Vec::with_capacity(len);
+        for chunk in self.chunks.into_iter().filter_map(|c| c.into_inner()) {
+            for slot in chunk.into_vec() {
+                if nodes.len() == len {
+                    break;
+                }
+                nodes.push(slot.into_inner().unwrap());
+            }
+        }
+        nodes
+    }
+}
+
+// A private copy of a definition body, with ids local to the copy (root first).
+// Workers instantiate from these instead of reading the definition nodes in the
+// arena, which keeps the definitions read-only while everything else rewrites.
+// Batch evaluation (batch.rs) shares them between nets in the same way.
+pub(crate) struct Template {
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    pub(crate) fn from_net(net: &Net, root: NodeId) -> Self {
+        let mut local: HashMap<NodeId, NodeId> = HashMap::new();
+        let mut order = vec![root];
+        local.insert(root, 0);
+
+        let mut i = 0;
+        while i < order.len() {
+            let node = net.get_node(order[i]);
+            for slot in 0..node.arity() {
+                let port = node.port(slot);
+                if !port.is_null() && !local.contains_key(&port.node) {
+                    local.insert(port.node, order.len());
+                    order.push(port.node);
+                }
+            }
+            i += 1;
+        }
+
+        let nodes = order
+            .iter()
+            .map(|&id| {
+                let mut node = net.get_node(id).clone();
+                for slot in 0..node.arity() {
+                    let port = node.port(slot);
+                    if !port.is_null() {
+                        node.set_port(slot, Port::new(local[&port.node], port.slot));
+                    }
+                }
+                node
+            })
+            .collect();
+        Self { nodes }
+    }
+
+    // Copies the body into a plain net and returns the id of its root.
+    // Every wire is connected once, from its lower end, so the redexes
+    // inside the body are queued once too. Its duplicators get fresh
+    // labels, as in Net::clone_subgraph.
+    pub(crate) fn instantiate_into(&self, net: &mut Net) -> NodeId {
+        let mut labels = HashMap::new();
+        let ids: Vec<NodeId> = self
+            .nodes
+            .iter()
+            .map(|node| {
+                let mut copy = node.clone();
+                copy.relabel(&mut labels, || net.fresh_label());
+                net.create_node(copy)
+            })
+            .collect();
+        for (local, node) in self.nodes.iter().enumerate() {
+            for slot in 0..node.arity() {
+                let port = node.port(slot);
+                // The clone still carries local ids; connect overwrites them
+                if !port.is_null() && (local, slot) < (port.node, port.slot) {
+                    net.connect(ids[local], slot, ids[port.node], port.slot);
+                }
+            }
+        }
+        ids[0]
+    }
+}
+
+// Counters for one parallel evaluation, folded into RuntimeStats by the runtime
+#[derive(Debug, Clone)]
+pub struct ParallelReport {
+    pub steps: usize,
+    pub steals: usize,
+    pub max_nodes: usize,
+    // The time the workers spent firing redexes, added up, over the wall
+    // time of the whole reduction: how many times faster than one thread
+    // doing the same work, if that thread fired redexes just as fast. Time
+    // lost retrying locks counts as work, so it is an upper bound.
+    pub speedup: f64,
+    // Interactions done in total divided by those done by the busiest worker,
+    // from 1 (one worker did everything) up to the number of workers (all
+    // did the same): how evenly the work was spread
+    pub balance: f64,
+    pub outcome: RunOutcome,
+}
+
+struct Shared {
+    arena: Arena,
+    book: HashMap<String, Template>,
+    queues: Vec<Mutex<VecDeque<Redex>>>,
+    pending: AtomicUsize, // redexes queued or being rewritten right now
+    live: AtomicUsize,
+    max_live: AtomicUsize,
+    limits: RunLimits,
+    cancel: CancelToken,
+    start: Instant,
+    claimed: AtomicUsize, // interactions workers have started, checked against the step limit
+    next_label: AtomicU32, // for the duplicators of unfolded definitions, see Net::clone_subgraph
+    halted: AtomicBool,   // set by the first worker that hits a limit or an error
+    outcome: Mutex<Option<RunOutcome>>,
+    error: Mutex<Option<String>>,
+}
+
+// Reduces the net on `threads` workers until no redex is left, a limit is hit,
+// the run is cancelled or a rule fails. The net is put back together (including whatever was still
+// queued) in every case, so a stopped run can be resumed.
+pub fn reduce(
+    net: &mut Net,
+    threads: usize,
+    limits: &RunLimits,
+    cancel: &CancelToken,
+) -> Result<ParallelReport, String> {
+    let threads = threads.max(1);
+    let book = net
+        .definitions()
+        .map(|(name, root)| (name.to_string(), Template::from_net(net, root)))
+        .collect();
+    let live = net.node_count();
+    let (nodes, redexes) = net.export();
+
+    let mut queues = vec![VecDeque::new(); threads];
+    for (i, redex) in redexes.iter().enumerate() {
+        queues[i % threads].push_back(*redex);
+    }
+
+    // Slots that were already free go to the workers' free lists
+    let mut free_lists = vec![Vec::new(); threads];
+    for (id, _) in nodes.iter().enumerate().filter(|(_, node)| node.is_none()) {
+        free_lists[id % threads].push(id);
+    }
+
+    let shared = Shared {
+        arena: Arena::new(nodes),
+        book,
+        queues: queues.into_iter().map(Mutex::new).collect(),
+        pending: AtomicUsize::new(redexes.len()),
+        live: AtomicUsize::new(live),
+        max_live: AtomicUsize::new(live),
+        limits: limits.clone(),
+        cancel: cancel.clone(),
+        start: Instant::now(),
+        claimed: AtomicUsize::new(0),
+        next_label: AtomicU32::new(net.next_label()),
+        halted: AtomicBool::new(false),
+        outcome: Mutex::new(None),
+        error: Mutex::new(None),
+    };
+
+    let started = Instant::now();
+    let workers: Vec<WorkerCounts> = thread::scope(|scope| {
+        let handles: Vec<_> = free_lists
+            .into_iter()
+            .enumerate()
+            .map(|(id, free)| {
+                let shared = &shared;
+                scope.spawn(move || {
+                    let mut worker = Worker::new(id, shared, free);
+                    worker.run();
+                    worker.into_counts()
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    let wall = started.elapsed();
+    let busy: Duration = workers.iter().map(|w| w.busy).sum();
+    let steps: usize = workers.iter().map(|w| w.steps).sum();
+    let busiest = workers.iter().map(|w| w.steps).max().unwrap_or(0);
+    let report = ParallelReport {
+        steps,
+        steals: workers.iter().map(|w| w.steals).sum(),
+        max_nodes: shared.max_live.load(Ordering::Relaxed),
+        speedup: if wall.is_zero() {
+            1.0
+        } else {
+            busy.as_secs_f64() / wall.as_secs_f64()
+        },
+        balance: if busiest == 0 {
+            1.0
+        } else {
+            steps as f64 / busiest as f64
+        },
+        outcome: shared
+            .outcome
+            .into_inner()
+            .unwrap()
+            .unwrap_or(RunOutcome::Normal),
+    };
+
+    let error = shared.error.into_inner().unwrap();
+    let remaining: Vec<Redex> = shared
+        .queues
+        .into_iter()
+        .flat_map(|q| q.into_inner().unwrap())
+        .collect();
+    net.import(shared.arena.into_nodes(), remaining)?;
+    net.claim_label(shared.next_label.into_inner() - 1)?;
+
+    match error {
+        Some(msg) => Err(msg),
+        None => Ok(report),
+    }
+}
+
+struct WorkerCounts {
+    steps: usize,
+    steals: usize,
+    busy: Duration,
+}
+
+struct Worker<'s> {
+    id: usize,
+    shared: &'s Shared,
+    free: Vec<NodeId>,
+    steps: usize,
+    steals: usize,
+    busy: Duration, // time spent firing redexes, locking included
+}
+
+impl<'s> Worker<'s> {
+    fn new(id: usize, shared: &'s Shared, free: Vec<NodeId>) -> Self {
+        Self {
+            id,
+            shared,
+            free,
+            steps: 0,
+            steals: 0,
+            busy: Duration::ZERO,
+        }
+    }
+
+    // Keeps only the counters, which have to outlive the thread scope
+    fn into_counts(self) -> WorkerCounts {
+        WorkerCounts {
+            steps: self.steps,
+            steals: self.steals,
+            busy: self.busy,
+        }
+    }
+
+    fn run(&mut self) {
+        loop {
+            if self.shared.halted.load(Ordering::SeqCst) {
+                return;
+            }
+            match self.pop().or_else(|| self.steal()) {
+                Some(redex) => {
+                    if let Some(outcome) = self.limit_reached() {
+                        *self.shared.outcome.lock().unwrap() = Some(outcome);
+                        self.halt(redex);
+                    } else {
+                        let start = Instant::now();
+                        self.fire(redex);
+                        self.busy += start.elapsed();
+                    }
+                    self.shared.pending.fetch_sub(1, Ordering::SeqCst);
+                }
+                None => {
+                    if self.shared.pending.load(Ordering::SeqCst) == 0 {
+                        return;
+                    }
+                    thread::yield_now();
+                }
+            }
+        }
+    }
+
+    fn limit_reached(&self) -> Option<RunOutcome> {
+        let shared = self.shared;
+        if shared.cancel.is_cancelled() {
+            return Some(RunOutcome::Cancelled);
+        }
+        let steps = shared.claimed.fetch_add(1, Ordering::SeqCst);
+        let nodes = shared.live.load(Ordering::SeqCst);
+        shared.limits.exceeded(steps, nodes, shared.start)
+    }
+
+    // Stops every worker; the redex that was about to fire goes back in the queue
+    fn halt(&self, redex: Redex) {
+        self.shared.halted.store(true, Ordering::SeqCst);
+        self.shared.queues[self.id].lock().unwrap().push_back(redex);
+    }
+
+    fn pop(&self) -> Option<Redex> {
+        self.shared.queues[self.id].lock().unwrap().pop_back()
+    }
+
+    fn steal(&mut self) -> Option<Redex> {
+        let n = self.shared.queues.len();
+        for k in 1..n {
+            let victim = (self.id + k) % n;
+            if let Some(redex) = self.shared.queues[victim].lock().unwrap().pop_front() {
+                self.steals += 1;
+                return Some(redex);
+            }
+        }
+        None
+    }
+
+    fn fire(&mut self, redex: Redex) {
+        let shared = self.shared;
+        let mut view = loop {
+            match LockedView::lock(shared, redex, &mut self.free) {
+                Some(view) => break view,
+                None => thread::yield_now(),
+            }
+        };
+
+        let result = apply_interaction(&mut view, redex);
+        let (redexes, created, freed) = view.finish();
+
+        if let Err(msg) = result {
+            let mut error = shared.error.lock().unwrap();
+            error.get_or_insert(msg);
+            // The failed redex goes back so the net stays whole
+            self.halt(redex);
+            return;
+        }
+
+        self.steps += 1;
+        let live = shared.live.fetch_add(created, Ordering::SeqCst) + created;
+        shared.max_live.fetch_max(live, Ordering::SeqCst);
+        shared.live.fetch_sub(freed.len(), Ordering::SeqCst);
+        self.free.extend(freed);
+
+        if !redexes.is_empty() {
+            shared.pending.fetch_add(redexes.len(), Ordering::SeqCst);
+            shared.queues[self.id].lock().unwrap().extend(redexes);
+        }
+    }
+}
+
+// Exclusive access to the nodes touched by one redex, plus the nodes it creates.
+// Implements NetOps so interaction.rs can rewrite through it unchanged.
+struct LockedView<'s, 'w> {
+    shared: &'s Shared,
+    free: &'w mut Vec<NodeId>,
+    guards: Vec<(NodeId, MutexGuard<'s, Option<Node>>)>, // a handful, so a Vec beats hashing
+    redexes: Vec<Redex>,
+    freed: Vec<NodeId>,
+    created: usize,
+}
+
+impl<'s, 'w> LockedView<'s, 'w> {
+    // Locks the redex and its auxiliary neighbours, or returns None if another
+    // worker holds one of them. A wire only changes under the locks of both
+    // nodes it joins, so once the redex nodes are held their neighbours stay put.
+    fn lock(shared: &'s Shared, redex: Redex, free: &'w mut Vec<NodeId>) -> Option<Self> {
+        let mut view = Self {
+            shared,
+            free,
+            guards: Vec::with_capacity(8),
+            redexes: Vec::new(),
+            freed: Vec::new(),
+            created: 0,
+        };
+        view.try_hold(redex.a)?;
+        view.try_hold(redex.b)?;
+
+        let mut neighbours = Vec::with_capacity(4);
+        for id in [redex.a, redex.b] {
+            neighbours.extend(aux_neighbours(view.get_node(id)));
+        }
+        for id in neighbours {
+            if !view.holds(id) {
+                view.try_hold(id)?;
+            }
+        }
+        Some(view)
+    }
+
+    fn holds(&self, id: NodeId) -> bool {
+        self.guards.iter().any(|(locked, _)| *locked == id)
+    }
+
+    fn try_hold(&mut self, id: NodeId) -> Option<()> {
+        match self.shared.arena.slot(id).try_lock() {
+            Ok(guard) => {
+                self.guards.push((id, guard));
+                Some(())
+            }
+            Err(TryLockError::WouldBlock) => None,
+            Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+        }
+    }
+
+    fn guard(&mut self, id: NodeId) -> &mut Option<Node> {
+        let (_, guard) = self
+            .guards
+            .iter_mut()
+            .find(|(locked, _)| *locked == id)
+            .expect("node is not locked");
+        guard
+    }
+
+    fn node_mut(&mut self, id: NodeId) -> &mut Node {
+        self.guard(id).as_mut().expect("node is not live")
+    }
+
+    // Takes a slot for a node and locks it, without wiring anything yet
+    fn place(&mut self, node: Node) -> NodeId {
+        let id = self.shared.arena.alloc(self.free);
+        let mut guard = self.shared.arena.slot(id).lock().unwrap();
+        *guard = Some(node);
+        self.guards.push((id, guard));
+        self.created += 1;
+        id
+    }
+
+    // Clears the consumed slots and releases every lock
+    fn finish(mut self) -> (Vec<Redex>, usize, Vec<NodeId>) {
+        for id in std::mem::take(&mut self.freed) {
+            *self.guard(id) = None;
+            self.freed.push(id);
+        }
+        (self.redexes, self.created, self.freed)
+    }
+}
+
+fn aux_neighbours(node: &Node) -> impl Iterator<Item = NodeId> + '_ {
+    (1..node.arity())
+        .map(|slot| node.port(slot))
+        .filter(|port| !port.is_null())
+        .map(|port| port.node)
+}
+
+impl NetOps for LockedView<'_, '_> {
+    fn get_node(&self, id: NodeId) -> &Node {
+        self.guards
+            .iter()
+            .find(|(locked, _)| *locked == id)
+            .and_then(|(_, guard)| guard.as_ref())
+            .expect("node is not live")
+    }
+
+    fn port(&self, id: NodeId, slot: usize) -> Port {
+        self.get_node(id).port(slot)
+    }
+
+    fn connect(&mut self, a: NodeId, a_slot: usize, b: NodeId, b_slot: usize) {
+        self.node_mut(a).set_port(a_slot, Port::new(b, b_slot));
+        self.node_mut(b).set_port(b_slot, Port::new(a, a_slot));
+        if a_slot == 0 && b_slot == 0 && self.get_node(a).interacts_with(self.get_node(b)) {
+            self.redexes.push(Redex { a, b });
+        }
+    }
+
+    fn disconnect(&mut self, id: NodeId, slot: usize) {
+        self.node_mut(id).set_port(slot, Port::null());
+    }
+
+    fn create_node(&mut self, node: Node) -> NodeId {
+        self.place(node)
+    }
+
+    fn free_node(&mut self, id: NodeId) {
+        if !self.freed.contains(&id) {
+            self.freed.push(id);
+        }
+    }
+
+    fn instantiate(&mut self, name: &str) -> Option<NodeId> {
+        let shared = self.shared;
+        let template = shared.book.get(name)?;
+        let ids: Vec<NodeId> = (0..template.nodes.len())
+            .map(|_| shared.arena.alloc(self.free))
+            .collect();
+
+        // Wire the copy up before it goes into the arena, so no lookups are needed
+        let mut labels = HashMap::new();
+        for (local, node) in template.nodes.iter().enumerate() {
+            let mut copy = node.clone();
+            copy.relabel(&mut labels, || {
+                shared.next_label.fetch_add(1, Ordering::Relaxed)
+            });
+            for slot in 0..node.arity() {
+                let port = node.port(slot);
+                if port.is_null() {
+                    continue;
+                }
+                copy.set_port(slot, Port::new(ids[port.node], port.slot));
+                // Active pairs inside the body are queued once, from their lower end
+                if slot == 0
+                    && port.slot == 0
+                    && port.node > local
+                    && node.interacts_with(&template.nodes[port.node])
+                {
+                    self.redexes.push(Redex {
+                        a: ids[local],
+                        b: ids[port.node],
+                    });
+                }
+            }
+            let mut guard = shared.arena.slot(ids[local]).lock().unwrap();
+            *guard = Some(copy);
+            self.guards.push((ids[local], guard));
+        }
+        self.created += ids.len();
+        Some(ids[0])
+    }
+}
@@ -0,0 +1,1078 @@
+// || shree ganesh ||
+//
+// The fuel injection system: turns .hvm source text into a net the engine
+// can run. A program is a list of definitions:
+//
+//   // comments run to the end of the line
+//   double n = n + n
+//   main = double (let x = 3; x * x)
+//
+// A definition starts at the beginning of a line; lines that continue its
+// body are indented. A term is one of
+//
+//   λx y. body   or  \x y. body     lambdas, as many binders as wanted
+//   f a b                           application, to the left
+//   let x = value; body             sugar for (λx. body) value
+//   42                              a Church numeral
+//   a + b, a - b, a * b             arithmetic on Church numerals
+//   a == b, a != b, a < b, ...      comparisons, giving Church booleans
+//   #7{a (f b)}                     a constructor with tag 7 and two fields
+//   name                            a bound variable, or else a definition
+//
+// The operators are plain calls into a small prelude of definitions written
+// in this same syntax (PRELUDE below), which is linked into any program that
+// uses them. A definition of the same name in the program wins where the
+// program uses the name, but not inside the prelude or behind an operator:
+// prelude definitions go into the net under names no program can write
+// (prelude.pred), so `pred n = n` does not change what `3 - 1` means.
+//
+// Errors come back as Diagnostics (diagnostic.rs) pointing into the source,
+// every one found rather than just the first, and the compiled net remembers
+// where each reference was written.
+//
+// compile() builds one template per definition and plugs `main`, if there
+// is one, into the root. Definitions that are not functions at the top (an
+// application, say) are eta-expanded, because a reference unfolds by meeting
+// its definition's principal port; Church numerals survive this unchanged.
+//
+// References unfold as soon as they meet another agent, so a definition that
+// calls itself unconditionally only terminates under lazy evaluation
+// (Runtime::set_lazy), which unfolds only what the result depends on.
+//
+// Each definition is linearized on the way (linear.rs): a variable used
+// several times is copied by duplicators with a label for its binding site,
+// and compile_with_sharing() tells where that happened.
+
+use crate::diagnostic::{Diagnostic, Source, SourceMap, Span};
+use crate::lambda;
+use crate::linear::{Linearizer, Sharing};
+use crate::net::Net;
+use crate::term::{LAMBDA_TAG, Term, encode};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+const PRELUDE: &str = "
+true t f = t
+false t f = f
+not p = p false true
+and p q = p q false
+or p q = p true q
+succ n f x = f (n f x)
+pred n f x = n (λg h. h (g f)) (λu. x) (λu. u)
+is_zero n = n (λx. false) true
+add m n f x = m f (n f x)
+sub m n = n pred m
+mul m n f = m (n f)
+le m n = is_zero (sub m n)
+ge m n = le n m
+lt m n = not (le n m)
+gt m n = not (le m n)
+eq m n = and (le m n) (le n m)
+ne m n = not (eq m n)
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    // The prelude definition the operator stands for
+    pub fn definition(&self) -> &'static str {
+        match self {
+            Op::Add => "add",
+            Op::Sub => "sub",
+            Op::Mul => "mul",
+            Op::Eq => "eq",
+            Op::Ne => "ne",
+            Op::Lt => "lt",
+            Op::Le => "le",
+            Op::Gt => "gt",
+            Op::Ge => "ge",
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            Op::Add => "+",
+            Op::Sub => "-",
+            Op::Mul => "*",
+            Op::Eq => "==",
+            Op::Ne => "!=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Name(String, Span), // a variable or a definition, told apart when compiling
+    Lam(String, Span, Box<Expr>), // with the span of the binder
+    App(Box<Expr>, Box<Expr>),
+    Let(String, Span, Box<Expr>, Box<Expr>),
+    Num(u64),
+    Op(Op, Box<Expr>, Box<Expr>),
+    Con(i32, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Definition {
+    pub name: String,
+    pub span: Span,                  // of the name
+    pub params: Vec<(String, Span)>, // `f x y = body` is `f = λx y. body`
+    pub body: Expr,
+    // Comment lines above it and the ones inside it, with "" for a blank
+    // line, so that formatting (formatter.rs) keeps them
+    pub leading: Vec<String>,
+    pub trailing: Option<String>, // the comment after it on its last line
+    pub moved: Vec<Span>,         // comments after code on its other lines, now in `leading`
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Program {
+    pub source: Arc<Source>, // the spans in the definitions point into it
+    pub definitions: Vec<Definition>,
+    pub trailing: Vec<String>, // comment lines after the last definition
+}
+
+pub fn parse(text: &str) -> Result<Program, Vec<Diagnostic>> {
+    parse_source(Source::new("<input>", text))
+}
+
+pub fn parse_file(path: impl AsRef<Path>) -> Result<Program, Vec<Diagnostic>> {
+    parse_source(read_source(path.as_ref())?)
+}
+
+fn read_source(path: &Path) -> Result<Source, Vec<Diagnostic>> {
+    let name = path.display().to_string();
+    let text = fs::read_to_string(path).map_err(|e| {
+        vec![Diagnostic::in_file(
+            &name,
+            format!("cannot read the file: {}", e),
+        )]
+    })?;
+    Ok(Source::new(&name, &text))
+}
+
+// Every syntax error in the source, and every name defined twice, come back
+// together: after an error the parser skips ahead to the next line that
+// starts a definition and carries on from there.
+pub fn parse_source(source: Source) -> Result<Program, Vec<Diagnostic>> {
+    let (program, errors) = parse_partial(source);
+    if errors.is_empty() {
+        Ok(program)
+    } else {
+        Err(errors)
+    }
+}
+
+// All that is wrong with a source file in one go: its syntax errors,
+// duplicate definitions, and names that refer to nothing. `externs` are the
+// names the program may use without defining them (see compile_with_externs).
+pub fn check(source: Source, externs: &[&str]) -> Vec<Diagnostic> {
+    let (program, mut errors) = parse_partial(source);
+    errors.extend(undefined_names(&program, externs));
+    errors.sort_by_key(|e| (e.line, e.column));
+    errors
+}
+
+pub fn check_file(path: impl AsRef<Path>, externs: &[&str]) -> Vec<Diagnostic> {
+    match read_source(path.as_ref()) {
+        Ok(source) => check(source, externs),
+        Err(errors) => errors,
+    }
+}
+
+// A single term in the syntax of definition bodies, as a closed lambda
+// term (see lambda.rs). It may use the prelude, operators included, whose
+// definitions are written out in place; any other free name is an error.
+pub fn parse_term(text: &str) -> Result<Term, Vec<Diagnostic>> {
+    let source = Source::new("<term>", text);
+    let (tokens, _, mut errors) = lex(&source);
+    let mut parser = Parser {
+        source: &source,
+        tokens,
+        at: 0,
+        errors: Vec::new(),
+    };
+    let expr = parser
+        .term()
+        .and_then(|expr| parser.expect(Tok::End).map(|_| expr));
+    let expr = match expr {
+        Ok(expr) if errors.is_empty() => expr,
+        Ok(_) => return Err(errors),
+        Err(error) => {
+            errors.push(error);
+            errors.sort_by_key(|e| (e.line, e.column));
+            return Err(errors);
+        }
+    };
+
+    let prelude = prelude();
+    let names = prelude_names(&prelude);
+    free_names(&expr, &mut Vec::new(), &mut |name, span| {
+        if !names.contains_key(name) {
+            let msg = format!("unbound variable `{}`", name);
+            errors.push(Diagnostic::new(&source, span, msg));
+        }
+    });
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let source = Arc::new(source);
+    let mut linear = Linearizer::new();
+    let mut lowering = Lowering {
+        definition: "<term>",
+        source: &source,
+        names: &names,
+        linear: &mut linear,
+        bound: Vec::new(),
+        references: Vec::new(),
+    };
+    let term = lowering.lower(&expr).map_err(|e| vec![e])?;
+    let mut inlining = Inlining {
+        prelude: &prelude,
+        names: &names,
+        linear: &mut linear,
+        done: HashMap::new(),
+    };
+    Ok(inlining.inline(term))
+}
+
+// Replaces every reference in `term` with the prelude definition it names.
+// Those are closed and not recursive, so this ends and captures nothing.
+// Each copy gets labels of its own, so two of them never mistake each
+// other's duplicators for their own.
+struct Inlining<'a> {
+    prelude: &'a Program,
+    names: &'a Names,
+    linear: &'a mut Linearizer,
+    done: HashMap<String, Term>, // the definitions inlined so far, written out
+}
+
+impl Inlining<'_> {
+    fn inline(&mut self, term: Term) -> Term {
+        match term {
+            Term::Ref(name) => {
+                if let Some(term) = self.done.get(&name) {
+                    return self.linear.relabel(term);
+                }
+                let definition = self
+                    .prelude
+                    .definitions
+                    .iter()
+                    .find(|d| prelude_name(&d.name) == name);
+                let (term, _) = lower_definition(
+                    definition.unwrap(),
+                    &self.prelude.source,
+                    self.names,
+                    self.linear,
+                )
+                .expect("the prelude lowers");
+                let term = self.inline(term);
+                self.done.insert(name, term.clone());
+                term
+            }
+            Term::Lam(var, body) => Term::Lam(var, Box::new(self.inline(*body))),
+            Term::App(fun, arg) => Term::app(self.inline(*fun), self.inline(*arg)),
+            Term::Con(tag, left, right) => Term::Con(
+                tag,
+                Box::new(self.inline(*left)),
+                Box::new(self.inline(*right)),
+            ),
+            Term::Dup(label, a, b, value, body) => Term::Dup(
+                label,
+                a,
+                b,
+                Box::new(self.inline(*value)),
+                Box::new(self.inline(*body)),
+            ),
+            term => term,
+        }
+    }
+}
+
+// The definitions that parsed, the first of each name only, and an error
+// for everything else
+fn parse_partial(source: Source) -> (Program, Vec<Diagnostic>) {
+    let (tokens, comments, mut errors) = lex(&source);
+    let mut parser = Parser {
+        source: &source,
+        tokens,
+        at: 0,
+        errors: Vec::new(),
+    };
+    let parsed = parser.program();
+    errors.append(&mut parser.errors);
+
+    // Comments go with the definition below them; the ones inside a
+    // definition move up above it, but one after it on its last line stays
+    // there
+    let text = source.text.as_str();
+    let mut cursor = 0;
+    let mut definitions: Vec<Definition> = Vec::new();
+    for (mut definition, end) in parsed {
+        let start = definition.span.start;
+        let next_line = text[end..].find('\n').map_or(text.len(), |i| end + i + 1);
+        definition.leading = layout_lines(text, cursor, start);
+        if cursor == 0 && definition.leading.first().is_some_and(|l| l.is_empty()) {
+            definition.leading.remove(0);
+        }
+        for comment in comments
+            .iter()
+            .filter(|c| start <= c.start && c.start < next_line)
+        {
+            let line = text[comment.start..comment.end].trim_end().to_string();
+            if comment.start >= end {
+                definition.trailing = Some(line);
+                continue;
+            }
+            let line_start = text[..comment.start].rfind('\n').map_or(0, |i| i + 1);
+            if !text[line_start..comment.start].trim().is_empty() {
+                definition.moved.push(*comment);
+            }
+            definition.leading.push(line);
+        }
+        cursor = next_line;
+        match definitions.iter().find(|d| d.name == definition.name) {
+            Some(first) => {
+                let (line, column) = source.location(first.span.start);
+                let msg = format!(
+                    "`{}` is defined twice, first at {}:{}",
+                    definition.name, line, column
+                );
+                errors.push(Diagnostic::new(&source, definition.span, msg));
+            }
+            None => definitions.push(definition),
+        }
+    }
+    let mut trailing = layout_lines(text, cursor, text.len());
+    if trailing.last().is_some_and(|l| l.is_empty()) {
+        trailing.pop();
+    }
+
+    errors.sort_by_key(|e| (e.line, e.column));
+    let program = Program {
+        source: Arc::new(source),
+        definitions,
+        trailing,
+    };
+    (program, errors)
+}
+
+// The comment lines in text[from..to], a stretch of whole lines, and ""
+// for every run of blank lines
+fn layout_lines(text: &str, from: usize, to: usize) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in text[from..to].lines().map(str::trim) {
+        if line.starts_with("//") {
+            lines.push(line.to_string());
+        } else if line.is_empty() && lines.last().is_none_or(|l| !l.is_empty()) {
+            lines.push(String::new());
+        }
+    }
+    lines
+}
+
+// ---- Lexing ----
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Tok {
+    Name(String),
+    Num(u64),
+    Let,
+    Lambda,
+    Dot,
+    Equals,
+    Semi,
+    Hash,
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    Op(Op),
+    End,
+}
+
+impl fmt::Display for Tok {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tok::Name(name) => write!(f, "`{}`", name),
+            Tok::Num(n) => write!(f, "`{}`", n),
+            Tok::Let => write!(f, "`let`"),
+            Tok::Lambda => write!(f, "`λ`"),
+            Tok::Dot => write!(f, "`.`"),
+            Tok::Equals => write!(f, "`=`"),
+            Tok::Semi => write!(f, "`;`"),
+            Tok::Hash => write!(f, "`#`"),
+            Tok::LParen => write!(f, "`(`"),
+            Tok::RParen => write!(f, "`)`"),
+            Tok::LBrace => write!(f, "`{{`"),
+            Tok::RBrace => write!(f, "`}}`"),
+            Tok::Op(op) => write!(f, "`{}`", op),
+            Tok::End => write!(f, "the end of the file"),
+        }
+    }
+}
+
+// Every token with the part of the source it was read from, and where the
+// comments are. Characters that start no token are reported and skipped.
+fn lex(source: &Source) -> (Vec<(Tok, Span)>, Vec<Span>, Vec<Diagnostic>) {
+    let text = source.text.as_str();
+    let mut tokens = Vec::new();
+    let mut comments = Vec::new();
+    let mut errors = Vec::new();
+    let mut chars = text.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        chars.next();
+        let next = chars.peek().map(|&(_, c)| c);
+        let tok = match c {
+            _ if c.is_whitespace() => continue,
+            '/' if next == Some('/') => {
+                while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+                let end = chars.peek().map_or(text.len(), |&(i, _)| i);
+                comments.push(Span::new(start, end));
+                continue;
+            }
+            'λ' | '\\' => Tok::Lambda,
+            '.' => Tok::Dot,
+            ';' => Tok::Semi,
+            '#' => Tok::Hash,
+            '(' => Tok::LParen,
+            ')' => Tok::RParen,
+            '{' => Tok::LBrace,
+            '}' => Tok::RBrace,
+            '+' => Tok::Op(Op::Add),
+            '-' => Tok::Op(Op::Sub),
+            '*' => Tok::Op(Op::Mul),
+            '=' | '!' | '<' | '>' => {
+                let with_eq = chars.next_if(|&(_, c)| c == '=').is_some();
+                match (c, with_eq) {
+                    ('=', false) => Tok::Equals,
+                    ('=', true) => Tok::Op(Op::Eq),
+                    ('!', true) => Tok::Op(Op::Ne),
+                    ('<', false) => Tok::Op(Op::Lt),
+                    ('<', true) => Tok::Op(Op::Le),
+                    ('>', false) => Tok::Op(Op::Gt),
+                    ('>', true) => Tok::Op(Op::Ge),
+                    _ => {
+                        let span = Span::new(start, start + 1);
+                        errors.push(Diagnostic::new(source, span, "expected `!=`"));
+                        continue;
+                    }
+                }
+            }
+            _ if c.is_ascii_digit() => {
+                let mut end = start + 1;
+                while let Some((i, _)) = chars.next_if(|&(_, c)| c.is_ascii_digit()) {
+                    end = i + 1;
+                }
+                let n = text[start..end].parse().unwrap_or_else(|_| {
+                    let span = Span::new(start, end);
+                    errors.push(Diagnostic::new(source, span, "number too large"));
+                    0
+                });
+                Tok::Num(n)
+            }
+            _ if c.is_alphabetic() || c == '_' => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, c)) = chars.next_if(|&(_, c)| is_name_char(c)) {
+                    end = i + c.len_utf8();
+                }
+                match &text[start..end] {
+                    "let" => Tok::Let,
+                    name => Tok::Name(name.to_string()),
+                }
+            }
+            _ => {
+                let msg = format!("unexpected character `{}`", c);
+                let span = Span::new(start, start + c.len_utf8());
+                errors.push(Diagnostic::new(source, span, msg));
+                continue;
+            }
+        };
+        let end = chars.peek().map_or(text.len(), |&(i, _)| i);
+        tokens.push((tok, Span::new(start, end)));
+    }
+    tokens.push((Tok::End, Span::new(text.len(), text.len())));
+    (tokens, comments, errors)
+}
+
+fn is_name_char(c: char) -> bool {
+    (c.is_alphanumeric() || c == '_' || c == '\'') && c != 'λ'
+}
+
+// ---- Parsing ----
+
+struct Parser<'s> {
+    source: &'s Source,
+    tokens: Vec<(Tok, Span)>,
+    at: usize,
+    errors: Vec<Diagnostic>, // of the definitions given up on
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> &Tok {
+        &self.tokens[self.at].0
+    }
+
+    fn advance(&mut self) -> Tok {
+        let tok = self.tokens[self.at].0.clone();
+        if tok != Tok::End {
+            self.at += 1;
+        }
+        tok
+    }
+
+    fn span(&self) -> Span {
+        self.tokens[self.at].1
+    }
+
+    fn error(&self, msg: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(self.source, self.span(), msg)
+    }
+
+    fn unexpected(&self, wanted: &str) -> Diagnostic {
+        self.error(format!("expected {}, found {}", wanted, self.peek()))
+    }
+
+    fn expect(&mut self, tok: Tok) -> Result<(), Diagnostic> {
+        if *self.peek() != tok {
+            return Err(self.unexpected(&tok.to_string()));
+        }
+        self.advance();
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<String, Diagnostic> {
+        match self.peek() {
+            Tok::Name(_) => match self.advance() {
+                Tok::Name(name) => Ok(name),
+                _ => unreachable!(),
+            },
+            _ => Err(self.unexpected("a name")),
+        }
+    }
+
+    // A name that binds a variable, and where it is
+    fn binder(&mut self) -> Result<(String, Span), Diagnostic> {
+        let span = self.span();
+        Ok((self.name()?, span))
+    }
+
+    // Whether the next token sits at the very start of a line, the only
+    // place a new definition may begin
+    fn at_line_start(&self) -> bool {
+        let offset = self.span().start;
+        offset == 0 || self.source.text[..offset].ends_with('\n')
+    }
+
+    // The definitions that parsed, each with the offset its last token ends at
+    fn program(&mut self) -> Vec<(Definition, usize)> {
+        let mut definitions = Vec::new();
+        while *self.peek() != Tok::End {
+            let start = self.at;
+            match self.definition() {
+                Ok(definition) => definitions.push((definition, self.tokens[self.at - 1].1.end)),
+                Err(error) => {
+                    self.errors.push(error);
+                    self.recover(start);
+                }
+            }
+        }
+        definitions
+    }
+
+    // Skips the rest of a broken definition, up to the next one
+    fn recover(&mut self, start: usize) {
+        if self.at == start {
+            self.advance();
+        }
+        while *self.peek() != Tok::End && !self.at_line_start() {
+            self.advance();
+        }
+    }
+
+    fn definition(&mut self) -> Result<Definition, Diagnostic> {
+        let span = self.span();
+        let name = self.name()?;
+        let mut params = Vec::new();
+        while let Tok::Name(_) = self.peek() {
+            params.push(self.binder()?);
+        }
+        self.expect(Tok::Equals)?;
+        let body = self.term()?;
+        Ok(Definition {
+            name,
+            span,
+            params,
+            body,
+            leading: Vec::new(),
+            trailing: None,
+            moved: Vec::new(),
+        })
+    }
+
+    fn term(&mut self) -> Result<Expr, Diagnostic> {
+        match self.peek() {
+            Tok::Lambda => {
+                self.advance();
+                let mut vars = vec![self.binder()?];
+                while let Tok::Name(_) = self.peek() {
+                    vars.push(self.binder()?);
+                }
+                self.expect(Tok::Dot)?;
+                let body = self.term()?;
+                Ok(vars.into_iter().rev().fold(body, |body, (var, span)| {
+                    Expr::Lam(var, span, Box::new(body))
+                }))
+            }
+            Tok::Let => {
+                self.advance();
+                let (var, span) = self.binder()?;
+                self.expect(Tok::Equals)?;
+                let value = self.term()?;
+                self.expect(Tok::Semi)?;
+                let body = self.term()?;
+                Ok(Expr::Let(var, span, Box::new(value), Box::new(body)))
+            }
+            _ => self.comparison(),
+        }
+    }
+
+    // Comparisons do not chain: a < b < c is an error
+    fn comparison(&mut self) -> Result<Expr, Diagnostic> {
+        let left = self.sum()?;
+        match self.operator() {
+            Some(op @ (Op::Eq | Op::Ne | Op::Lt | Op::Le | Op::Gt | Op::Ge)) => {
+                self.skip_operator(op)?;
+                let right = self.sum()?;
+                Ok(Expr::Op(op, Box::new(left), Box::new(right)))
+            }
+            _ => Ok(left),
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expr, Diagnostic> {
+        let mut left = self.product()?;
+        while let Some(op @ (Op::Add | Op::Sub)) = self.operator() {
+            self.skip_operator(op)?;
+            let right = self.product()?;
+            left = Expr::Op(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn product(&mut self) -> Result<Expr, Diagnostic> {
+        let mut left = self.application()?;
+        while let Some(Op::Mul) = self.operator() {
+            self.skip_operator(Op::Mul)?;
+            let right = self.application()?;
+            left = Expr::Op(Op::Mul, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    // The operator up next, unless it starts a line and so cannot continue
+    // the term before it
+    fn operator(&self) -> Option<Op> {
+        match self.peek() {
+            Tok::Op(op) if !self.at_line_start() => Some(*op),
+            _ => None,
+        }
+    }
+
+    // Steps past the operator up next. Its right operand has to be on the
+    // same line: what starts the next one is the next definition.
+    fn skip_operator(&mut self, op: Op) -> Result<(), Diagnostic> {
+        let span = self.span();
+        self.advance();
+        if *self.peek() == Tok::End || self.at_line_start() {
+            let msg = format!("operator `{}` has no right operand", op);
+            return Err(Diagnostic::new(self.source, span, msg));
+        }
+        Ok(())
+    }
+
+    fn application(&mut self) -> Result<Expr, Diagnostic> {
+        let mut fun = self.atom()?;
+        while self.at_atom() && !self.at_line_start() {
+            let arg = self.atom()?;
+            fun = Expr::App(Box::new(fun), Box::new(arg));
+        }
+        // A lambda or a let as the last argument needs no parentheses
+        if matches!(self.peek(), Tok::Lambda | Tok::Let) && !self.at_line_start() {
+            let arg = self.term()?;
+            fun = Expr::App(Box::new(fun), Box::new(arg));
+        }
+        Ok(fun)
+    }
+
+    fn at_atom(&self) -> bool {
+        matches!(
+            self.peek(),
+            Tok::Name(_) | Tok::Num(_) | Tok::LParen | Tok::Hash
+        )
+    }
+
+    fn atom(&mut self) -> Result<Expr, Diagnostic> {
+        match self.peek() {
+            Tok::Name(_) => {
+                let span = self.span();
+                Ok(Expr::Name(self.name()?, span))
+            }
+            Tok::Num(n) => {
+                let n = *n;
+                self.advance();
+                Ok(Expr::Num(n))
+            }
+            Tok::LParen => {
+                self.advance();
+                let inner = self.term()?;
+                self.expect(Tok::RParen)?;
+                Ok(inner)
+            }
+            Tok::Hash => {
+                self.advance();
+                let tag = match self.peek() {
+                    Tok::Num(n) => i32::try_from(*n).ok().filter(|&tag| tag != LAMBDA_TAG),
+                    _ => None,
+                };
+                let Some(tag) = tag else {
+                    let msg = format!("expected a constructor tag from 1 to {}", i32::MAX);
+                    return Err(self.error(msg));
+                };
+                self.advance();
+                // The fields are atoms, anything bigger goes in parentheses
+                self.expect(Tok::LBrace)?;
+                let left = self.atom()?;
+                let right = self.atom()?;
+                self.expect(Tok::RBrace)?;
+                Ok(Expr::Con(tag, Box::new(left), Box::new(right)))
+            }
+            _ => Err(self.unexpected("a term")),
+        }
+    }
+}
+
+// ---- Compiling ----
+
+// Builds the net for a program: a template for every definition it needs
+// (prelude ones included) and `main`, if present, plugged into the root
+pub fn compile(program: &Program) -> Result<Net, Vec<Diagnostic>> {
+    compile_with_externs(program, &[])
+}
+
+// Like compile, for a program that also refers to names which only exist
+// once it runs: host functions (host.rs) or the definitions of a batch
+// library (batch.rs). If one is still missing then, the error points at
+// the place in the source that refers to it.
+pub fn compile_with_externs(program: &Program, externs: &[&str]) -> Result<Net, Vec<Diagnostic>> {
+    compile_with_sharing(program, externs).map(|(net, _)| net)
+}
+
+// Like compile_with_externs, and also tells where the program's own
+// definitions share or drop a variable: the duplicators and erasers
+// linearization put in (linear.rs), in the order they appear in the source
+pub fn compile_with_sharing(
+    program: &Program,
+    externs: &[&str],
+) -> Result<(Net, Vec<Sharing>), Vec<Diagnostic>> {
+    let errors = undefined_names(program, externs);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let prelude = prelude();
+    // The prelude only sees itself. The program sees its own definitions,
+    // the externs and then the prelude, in that order.
+    let inside_prelude = prelude_names(&prelude);
+    let mut names = inside_prelude.clone();
+    for name in externs {
+        names.insert(name.to_string(), name.to_string());
+    }
+    for definition in &program.definitions {
+        names.insert(definition.name.clone(), definition.name.clone());
+    }
+    let mut sources: HashMap<String, (&Definition, &Arc<Source>, &Names)> = HashMap::new();
+    for definition in &prelude.definitions {
+        let entry = (definition, &prelude.source, &inside_prelude);
+        sources.insert(prelude_name(&definition.name), entry);
+    }
+    for definition in &program.definitions {
+        let entry = (definition, &program.source, &names);
+        sources.insert(definition.name.clone(), entry);
+    }
+
+    // Lower what the program needs, following references from its own
+    // definitions, so unused prelude entries stay out of the net
+    let mut source_map = SourceMap::new();
+    let mut linear = Linearizer::new();
+    let mut terms: Vec<(String, Term)> = Vec::new();
+    let mut wanted: Vec<String> = program.definitions.iter().map(|d| d.name.clone()).collect();
+    let mut seen: HashSet<String> = wanted.iter().cloned().collect();
+    let mut referenced: HashSet<String> = HashSet::new();
+    while let Some(name) = wanted.pop() {
+        let (definition, source, names) = sources[&name];
+        let (term, spans) =
+            lower_definition(definition, source, names, &mut linear).map_err(|e| vec![e])?;
+        for (reference, span) in &spans {
+            source_map.add_reference(reference, &definition.name, source, *span);
+        }
+
+        for reference in references(&term) {
+            if sources.contains_key(&reference) && seen.insert(reference.clone()) {
+                wanted.push(reference.clone());
+            }
+            referenced.insert(reference);
+        }
+        terms.push((name, term));
+    }
+    terms.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut net = Net::new();
+    let cannot_build = |name: &str, msg: String| {
+        let (definition, source, _) = sources[name];
+        let msg = format!("cannot build `{}`: {}", definition.name, msg);
+        vec![Diagnostic::new(source, definition.span, msg)]
+    };
+    // main needs a template only when something refers back to it
+    let templates = terms
+        .iter()
+        .filter(|(name, _)| name != "main" || referenced.contains("main"));
+    for (name, term) in templates {
+        let term = match term {
+            Term::App(..) => eta_expand(term.clone()),
+            term => term.clone(),
+        };
+        let port = encode(&mut net, &term).map_err(|msg| cannot_build(name, msg))?;
+        net.add_definition(name, port.node);
+    }
+    if let Some((_, main)) = terms.iter().find(|(name, _)| *name == "main") {
+        let port = encode(&mut net, main).map_err(|msg| cannot_build("main", msg))?;
+        net.set_root(port.node, port.slot);
+    }
+    net.set_source_map(source_map);
+
+    let mut sharing: Vec<Sharing> = linear
+        .sharing
+        .into_iter()
+        .filter(|s| program.definitions.iter().any(|d| d.name == s.definition))
+        .collect();
+    sharing.sort_by_key(|s| s.binder.start);
+    Ok((net, sharing))
+}
+
+fn prelude() -> Program {
+    parse_source(Source::new("<prelude>", PRELUDE)).expect("the prelude parses")
+}
+
+// What each name a definition may use refers to: the name of a definition
+// in the net, or of an extern
+type Names = HashMap<String, String>;
+
+// The name a prelude definition goes into the net under. It has a dot in
+// it, so no program can write it.
+fn prelude_name(name: &str) -> String {
+    format!("prelude.{}", name)
+}
+
+fn prelude_names(prelude: &Program) -> Names {
+    prelude
+        .definitions
+        .iter()
+        .map(|d| (d.name.clone(), prelude_name(&d.name)))
+        .collect()
+}
+
+// A definition as a linear lambda term (see linear.rs), with where it
+// refers to what
+fn lower_definition(
+    definition: &Definition,
+    source: &Arc<Source>,
+    names: &Names,
+    linear: &mut Linearizer,
+) -> Result<(Term, Vec<(String, Span)>), Diagnostic> {
+    let mut lowering = Lowering {
+        definition: &definition.name,
+        source,
+        names,
+        linear,
+        bound: Vec::new(),
+        references: Vec::new(),
+    };
+    let mut term = lowering.lower_params(&definition.params, &definition.body)?;
+    for (param, _) in definition.params.iter().rev() {
+        term = Term::lam(param, term);
+    }
+    Ok((term, lowering.references))
+}
+
+// An error for every name in the program that is not bound where it is
+// used, nor defined in the program or the prelude, nor one of `externs`
+fn undefined_names(program: &Program, externs: &[&str]) -> Vec<Diagnostic> {
+    let prelude = prelude();
+    let defined: HashSet<&str> = prelude
+        .definitions
+        .iter()
+        .chain(&program.definitions)
+        .map(|d| d.name.as_str())
+        .chain(externs.iter().copied())
+        .collect();
+    let mut errors = Vec::new();
+    for definition in &program.definitions {
+        let mut bound = definition.params.iter().map(|(p, _)| p.clone()).collect();
+        free_names(&definition.body, &mut bound, &mut |name, span| {
+            if !defined.contains(name) {
+                let msg = format!("undefined name `{}`", name);
+                errors.push(Diagnostic::new(&program.source, span, msg));
+            }
+        });
+    }
+    errors
+}
+
+// Calls `found` with every name in `expr` that none of its own binders nor
+// `bound` account for
+fn free_names(expr: &Expr, bound: &mut Vec<String>, found: &mut impl FnMut(&str, Span)) {
+    match expr {
+        Expr::Name(name, span) => {
+            if !bound.contains(name) {
+                found(name, *span);
+            }
+        }
+        Expr::Lam(var, _, body) => {
+            bound.push(var.clone());
+            free_names(body, bound, found);
+            bound.pop();
+        }
+        Expr::Let(var, _, value, body) => {
+            free_names(value, bound, found);
+            bound.push(var.clone());
+            free_names(body, bound, found);
+            bound.pop();
+        }
+        Expr::App(a, b) | Expr::Op(_, a, b) | Expr::Con(_, a, b) => {
+            free_names(a, bound, found);
+            free_names(b, bound, found);
+        }
+        Expr::Num(_) => {}
+    }
+}
+
+// λx. (term x), with a name no source program can use
+fn eta_expand(term: Term) -> Term {
+    let var = "%eta";
+    Term::lam(var, Term::app(term, Term::var(var)))
+}
+
+// Turns the body of one definition into a plain lambda term
+struct Lowering<'a> {
+    definition: &'a str,
+    source: &'a Arc<Source>,
+    names: &'a Names, // everything a free name may refer to
+    linear: &'a mut Linearizer,
+    bound: Vec<(String, Vec<Span>)>, // the variables in scope, and where each is used
+    references: Vec<(String, Span)>,
+}
+
+impl Lowering<'_> {
+    fn lower(&mut self, expr: &Expr) -> Result<Term, Diagnostic> {
+        Ok(match expr {
+            Expr::Name(name, span) if self.is_bound(name) => {
+                let (_, uses) = self
+                    .bound
+                    .iter_mut()
+                    .rev()
+                    .find(|(v, _)| v == name)
+                    .unwrap();
+                uses.push(*span);
+                Term::Var(name.clone())
+            }
+            Expr::Name(name, span) if self.names.contains_key(name) => {
+                let target = self.names[name].clone();
+                self.references.push((target.clone(), *span));
+                Term::Ref(target)
+            }
+            Expr::Name(name, span) => {
+                let msg = format!("undefined name `{}`", name);
+                return Err(Diagnostic::new(self.source, *span, msg));
+            }
+            Expr::Lam(var, span, body) => {
+                Term::lam(var, self.lower_params(&[(var.clone(), *span)], body)?)
+            }
+            Expr::App(fun, arg) => Term::app(self.lower(fun)?, self.lower(arg)?),
+            Expr::Let(var, span, value, body) => {
+                let value = self.lower(value)?;
+                let body = self.lower_params(&[(var.clone(), *span)], body)?;
+                Term::app(Term::lam(var, body), value)
+            }
+            Expr::Num(n) => lambda::church(*n),
+            Expr::Op(op, left, right) => Term::app(
+                Term::app(Term::Ref(prelude_name(op.definition())), self.lower(left)?),
+                self.lower(right)?,
+            ),
+            Expr::Con(tag, left, right) => Term::Con(
+                *tag,
+                Box::new(self.lower(left)?),
+                Box::new(self.lower(right)?),
+            ),
+        })
+    }
+
+    fn is_bound(&self, name: &str) -> bool {
+        self.bound.iter().any(|(v, _)| v == name)
+    }
+
+    // Lowers `body` with `vars` in scope, the last one innermost, and makes
+    // each of them used exactly once in it
+    fn lower_params(&mut self, vars: &[(String, Span)], body: &Expr) -> Result<Term, Diagnostic> {
+        let depth = self.bound.len();
+        self.bound
+            .extend(vars.iter().map(|(var, _)| (var.clone(), Vec::new())));
+        let body = self.lower(body);
+        let scope = self.bound.split_off(depth);
+        let mut body = body?;
+        for ((var, binder), (_, uses)) in vars.iter().zip(scope).rev() {
+            body = self
+                .linear
+                .bind(self.definition, self.source, var, *binder, uses, body);
+        }
+        Ok(body)
+    }
+}
+
+// Names of the definitions a term refers to
+fn references(term: &Term) -> Vec<String> {
+    let mut found = Vec::new();
+    let mut stack = vec![term];
+    while let Some(term) = stack.pop() {
+        match term {
+            Term::Ref(name) => found.push(name.clone()),
+            Term::Lam(_, body) => stack.push(body),
+            Term::App(a, b) | Term::Con(_, a, b) | Term::Dup(_, _, _, a, b) => {
+                stack.push(a);
+                stack.push(b);
+            }
+            _ => {}
+        }
+    }
+    found
+}
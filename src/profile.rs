@@ -0,0 +1,234 @@
+// || shree ganesh ||
+//
+// Per-definition profiling. The Profiler is an observer that remembers, for
+// every live node, the chain of reference expansions ("the stack") that
+// created it. Unfolding a reference pushes the definition's name onto the
+// stack of the reference node; nodes made by any other rule inherit the stack
+// of the interaction that made them. Every interaction is charged to the
+// deeper of the two stacks meeting in it, which is the definition whose body
+// is being worked on.
+//
+// Direct recursion reuses the caller's frame, so a loop running a million
+// times makes one frame, not a million. The result can be read as a table
+// per definition or written as folded stacks, one line per stack. For
+// examples/factorial.hvm they start
+//
+//   (root);fact 4
+//   (root);fact;pair 3
+//   (root);fact;snd 2
+//   (root);fact;snd;prelude.false 20
+//   (root);fact;step 43
+//
+// (main is the root's own term, not a reference, so it has no frame), which
+// flamegraph.pl, inferno and speedscope render as they are.
+
+use crate::interaction::Rule;
+use crate::net::{Net, NodeId, NodeKind};
+use crate::observer::{InteractionEvent, RuntimeObserver};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+// The frame nodes that were in the net before profiling started belong to
+const ROOT_FRAME: &str = "(root)";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    Interactions,
+    Nodes, // nodes allocated
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DefinitionProfile {
+    pub name: String,
+    pub calls: usize,        // times a reference to it was unfolded
+    pub interactions: usize, // charged to its own body
+    pub nodes: usize,        // allocated by its own body
+    // Interactions charged to it or to anything it called
+    pub total_interactions: usize,
+}
+
+struct Frame {
+    parent: Option<usize>,
+    depth: usize,
+    name: String,
+    interactions: usize,
+    nodes: usize,
+    calls: usize,
+}
+
+pub struct Profiler {
+    frames: Vec<Frame>, // frame 0 is the root; a stack is identified by its top frame
+    children: HashMap<(usize, String), usize>,
+    owner: HashMap<NodeId, usize>, // stack of every live node that was created while profiling
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            frames: vec![Frame {
+                parent: None,
+                depth: 0,
+                name: ROOT_FRAME.to_string(),
+                interactions: 0,
+                nodes: 0,
+                calls: 0,
+            }],
+            children: HashMap::new(),
+            owner: HashMap::new(),
+        }
+    }
+
+    fn stack_of(&self, id: NodeId) -> usize {
+        self.owner.get(&id).copied().unwrap_or(0)
+    }
+
+    // The stack `caller` becomes once `name` is unfolded on top of it
+    fn call(&mut self, caller: usize, name: &str) -> usize {
+        if self.frames[caller].name == name && caller != 0 {
+            return caller;
+        }
+        if let Some(&frame) = self.children.get(&(caller, name.to_string())) {
+            return frame;
+        }
+        let frame = self.frames.len();
+        self.frames.push(Frame {
+            parent: Some(caller),
+            depth: self.frames[caller].depth + 1,
+            name: name.to_string(),
+            interactions: 0,
+            nodes: 0,
+            calls: 0,
+        });
+        self.children.insert((caller, name.to_string()), frame);
+        frame
+    }
+
+    // Names on the stack, outermost first
+    fn path(&self, mut frame: usize) -> Vec<&str> {
+        let mut names = vec![self.frames[frame].name.as_str()];
+        while let Some(parent) = self.frames[frame].parent {
+            frame = parent;
+            names.push(self.frames[frame].name.as_str());
+        }
+        names.reverse();
+        names
+    }
+
+    // One row per definition, the busiest first. Nodes and interactions from
+    // before the first reference was unfolded go to the "(root)" row.
+    pub fn table(&self) -> Vec<DefinitionProfile> {
+        let mut rows: HashMap<&str, DefinitionProfile> = HashMap::new();
+        for frame in &self.frames {
+            let row = rows
+                .entry(frame.name.as_str())
+                .or_insert_with(|| DefinitionProfile {
+                    name: frame.name.clone(),
+                    calls: 0,
+                    interactions: 0,
+                    nodes: 0,
+                    total_interactions: 0,
+                });
+            row.calls += frame.calls;
+            row.interactions += frame.interactions;
+            row.nodes += frame.nodes;
+        }
+        // Inclusive counts go to every definition on the stack, once
+        for (id, frame) in self.frames.iter().enumerate() {
+            let mut path = self.path(id);
+            path.sort_unstable();
+            path.dedup();
+            for name in path {
+                rows.get_mut(name).unwrap().total_interactions += frame.interactions;
+            }
+        }
+
+        let mut rows: Vec<DefinitionProfile> = rows.into_values().collect();
+        rows.sort_by(|a, b| {
+            b.interactions
+                .cmp(&a.interactions)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        rows
+    }
+
+    // Folded stacks weighted by `metric`, sorted so the output is stable
+    pub fn folded(&self, metric: Metric) -> String {
+        let mut lines: Vec<String> = self
+            .frames
+            .iter()
+            .enumerate()
+            .filter_map(|(id, frame)| {
+                let weight = match metric {
+                    Metric::Interactions => frame.interactions,
+                    Metric::Nodes => frame.nodes,
+                };
+                (weight > 0).then(|| format!("{} {}", self.path(id).join(";"), weight))
+            })
+            .collect();
+        lines.sort();
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+
+    pub fn write_folded(&self, path: impl AsRef<Path>, metric: Metric) -> Result<(), String> {
+        let path = path.as_ref();
+        std::fs::write(path, self.folded(metric))
+            .map_err(|e| format!("Cannot write {}: {}", path.display(), e))
+    }
+}
+
+impl RuntimeObserver for Profiler {
+    fn after_interaction(&mut self, _net: &Net, event: &InteractionEvent) {
+        let [a, b] = [event.redex.a, event.redex.b];
+        let (stack_a, stack_b) = (self.stack_of(a), self.stack_of(b));
+
+        let stack = match Rule::between(&event.kinds[0], &event.kinds[1]) {
+            Some(Rule::Expand) => {
+                let (caller, name) = match &event.kinds {
+                    [NodeKind::Ref(name), _] => (stack_a, name),
+                    [_, NodeKind::Ref(name)] => (stack_b, name),
+                    _ => unreachable!("an expansion always involves a reference"),
+                };
+                let frame = self.call(caller, name);
+                self.frames[frame].calls += 1;
+                frame
+            }
+            _ if self.frames[stack_b].depth > self.frames[stack_a].depth => stack_b,
+            _ => stack_a,
+        };
+
+        self.frames[stack].interactions += 1;
+        self.frames[stack].nodes += event.created.len();
+        // Freed ids may come straight back as created ones
+        for id in &event.freed {
+            self.owner.remove(id);
+        }
+        for &id in &event.created {
+            self.owner.insert(id, stack);
+        }
+    }
+}
+
+impl fmt::Display for Profiler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<24} {:>10} {:>14} {:>14} {:>12}",
+            "definition", "calls", "interactions", "(with callees)", "nodes"
+        )?;
+        for row in self.table() {
+            writeln!(
+                f,
+                "{:<24} {:>10} {:>14} {:>14} {:>12}",
+                row.name, row.calls, row.interactions, row.total_interactions, row.nodes
+            )?;
+        }
+        Ok(())
+    }
+}
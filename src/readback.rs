@@ -0,0 +1,555 @@
+// || shree ganesh ||
+//
+// Readback is the speedometer of the engine: once the runtime is done, it
+// starts from the root port and turns whatever is plugged in there back into
+// something a person (or the VORTEX front end) can read.
+//
+// It works in two stages:
+// 1. walk the net from the root and rebuild a lambda term (see term.rs for
+//    how lambdas and applications are laid out as Con nodes). A copy made
+//    by a duplicator is read through it: the walk notes which side of the
+//    Dup it came in by, and takes the same side out when it reaches the
+//    term being copied. Duplicators of different labels copy independently
+//    of each other (linear.rs), so each label keeps its own record.
+//    Variables then get fresh names, a, b, ... in the order they are bound,
+//    skipping any name a reference in the term already uses, so two terms
+//    are alike exactly when they print alike.
+// 2. decode that term as a number, boolean, tuple or list, according to the
+//    expected shape and the encoding the program was compiled with
+//
+// If either stage fails, the residual net hanging off the root is returned
+// instead, so there is always something to look at.
+
+use crate::lambda;
+use crate::net::{Net, Node, NodeId, Port};
+use crate::runtime::{RunOutcome, Runtime};
+use crate::term::{LAMBDA_TAG, Term};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+
+// Gives up on nets that are too large (or cyclic) to be a sensible term
+const MAX_READ_STEPS: usize = 1_000_000;
+// The walk is recursive; deeper than this would run out of stack first
+const MAX_READ_NESTING: usize = 2_000;
+
+// How data was encoded as lambda terms
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    // Data is its own fold: 2 = λf λx (f (f x)), [a, b] = λc λn ((c a) ((c b) n))
+    Church,
+    // Data is its own case analysis: 0 = λz λs z, n+1 = λz λs (s n),
+    // [] = λc λn n, h :: t = λc λn ((c h) t)
+    Scott,
+}
+
+// What the caller expects to find at the root. Encoded values are ambiguous
+// on their own (Church 0 and false are the same term), so readback is guided.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Shape {
+    Number,
+    Boolean,           // true = λt λf t, false = λt λf f in both encodings
+    Tuple(Vec<Shape>), // (a, b) = λp ((p a) b) in both encodings
+    List(Box<Shape>),
+    Term, // no decoding, just the lambda term
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Number(u64),
+    Boolean(bool),
+    Tuple(Vec<Value>),
+    List(Vec<Value>),
+    Term(Term),
+    Stream(Vec<Value>), // the start of a list lazy readback stopped reading
+    Residual(String),   // the part of the net readback could not make sense of
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Tuple(items) => {
+                let items: Vec<String> = items.iter().map(|v| v.to_string()).collect();
+                write!(f, "({})", items.join(", "))
+            }
+            Value::List(items) => {
+                let items: Vec<String> = items.iter().map(|v| v.to_string()).collect();
+                write!(f, "[{}]", items.join(", "))
+            }
+            Value::Stream(items) => {
+                let mut items: Vec<String> = items.iter().map(|v| v.to_string()).collect();
+                items.push("...".to_string());
+                write!(f, "[{}]", items.join(", "))
+            }
+            Value::Term(term) => write!(f, "{}", lambda::pretty(term)),
+            Value::Residual(net) => write!(f, "<residual net>\n{}", net),
+        }
+    }
+}
+
+// Reads the value at the root, falling back to the residual net on failure
+pub fn read_value(net: &Net, shape: &Shape, encoding: Encoding) -> Value {
+    read_term(net)
+        .ok()
+        .and_then(|term| decode(&term, shape, encoding))
+        .unwrap_or_else(|| Value::Residual(residual(net)))
+}
+
+// Rebuilds the lambda term plugged into the root
+pub fn read_term(net: &Net) -> Result<Term, String> {
+    let root = net.root().ok_or("the net has no root")?;
+    read_term_at(net, Port::new(root, 0))
+}
+
+// Rebuilds the lambda term on the other side of `from`
+pub fn read_term_at(net: &Net, from: Port) -> Result<Term, String> {
+    let term = Reader::new(net, usize::MAX).read(from, 0, &mut Exits::new())?;
+    Ok(fresh_names(&term))
+}
+
+// Lazy counterpart of read_value: the runtime only reduces the sub-terms the
+// walk actually asks for. Anything nested deeper than `depth` is left as a
+// Thunk, which is what makes infinite streams readable (as a prefix).
+// Err means the evaluation itself failed or was stopped.
+pub fn read_value_lazy(
+    runtime: &mut Runtime,
+    shape: &Shape,
+    encoding: Encoding,
+    depth: usize,
+) -> Result<Value, String> {
+    let root = runtime.get_net().root().ok_or("the net has no root")?;
+    let mut reader = Reader::new(&mut *runtime, depth);
+    let term = reader.read(Port::new(root, 0), 0, &mut Exits::new());
+    if let Some(msg) = reader.halted {
+        return Err(msg);
+    }
+
+    let value = term
+        .ok()
+        .map(|term| fresh_names(&term))
+        .and_then(|term| decode(&term, shape, encoding));
+    Ok(value.unwrap_or_else(|| Value::Residual(residual(runtime.get_net()))))
+}
+
+// Where the reader gets its net from: a finished net, or a lazy runtime
+// that reduces every wire just before the reader crosses it
+trait Source {
+    fn net(&self) -> &Net;
+    fn demand(&mut self, from: Port) -> Result<(), String>;
+}
+
+impl Source for &Net {
+    fn net(&self) -> &Net {
+        self
+    }
+
+    fn demand(&mut self, _from: Port) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl Source for &mut Runtime {
+    fn net(&self) -> &Net {
+        self.get_net()
+    }
+
+    fn demand(&mut self, from: Port) -> Result<(), String> {
+        match self.whnf(from)? {
+            RunOutcome::Normal => Ok(()),
+            outcome => Err(format!("evaluation stopped: {:?}", outcome)),
+        }
+    }
+}
+
+// For each Dup label, the auxiliary ports the walk entered duplicators of
+// that label through, the latest last
+type Exits = HashMap<u32, Vec<usize>>;
+
+struct Reader<S: Source> {
+    source: S,
+    names: HashMap<NodeId, String>, // variable name of every lambda met so far
+    steps: usize,
+    nesting: usize,         // calls to read() currently on the stack
+    depth: usize,           // deepest sub-term worth reading
+    halted: Option<String>, // why the source could not evaluate a wire
+}
+
+impl<S: Source> Reader<S> {
+    fn new(source: S, depth: usize) -> Self {
+        Reader {
+            source,
+            names: HashMap::new(),
+            steps: 0,
+            nesting: 0,
+            depth,
+            halted: None,
+        }
+    }
+
+    // Reads the term on the other side of `from`. `dups` remembers which
+    // auxiliary port each Dup on the way was entered through, so that when
+    // the walk later comes out of the principal port of a Dup with the same
+    // label it leaves by the same copy.
+    fn read(&mut self, from: Port, depth: usize, dups: &mut Exits) -> Result<Term, String> {
+        if self.nesting >= MAX_READ_NESTING {
+            return Err("the term is nested too deeply to read back".to_string());
+        }
+        self.nesting += 1;
+        let term = self.read_port(from, depth, dups);
+        self.nesting -= 1;
+        term
+    }
+
+    fn read_port(&mut self, from: Port, depth: usize, dups: &mut Exits) -> Result<Term, String> {
+        if depth > self.depth {
+            return Ok(Term::Thunk);
+        }
+        let at = self.follow(from, dups)?;
+        let net = self.source.net();
+        let id = at.node;
+        let next = depth + 1;
+        match net.get_node(id) {
+            Node::Con { tag, .. } if *tag == LAMBDA_TAG => match at.slot {
+                // Entered from the top: a lambda
+                0 => {
+                    let var = self.name(id);
+                    let body = self.read(Port::new(id, 2), next, dups)?;
+                    Ok(Term::Lam(var, Box::new(body)))
+                }
+                // Entered through the variable port: an occurrence of that variable
+                1 => Ok(Term::Var(self.name(id))),
+                // Entered through the result port: an application
+                _ => {
+                    let fun = self.read(Port::new(id, 0), next, &mut dups.clone())?;
+                    let arg = self.read(Port::new(id, 1), next, dups)?;
+                    Ok(Term::App(Box::new(fun), Box::new(arg)))
+                }
+            },
+            Node::Con { tag, .. } if at.slot == 0 => {
+                let tag = *tag;
+                let left = self.read(Port::new(id, 1), next, &mut dups.clone())?;
+                let right = self.read(Port::new(id, 2), next, dups)?;
+                Ok(Term::Con(tag, Box::new(left), Box::new(right)))
+            }
+            Node::Con { tag, .. } => Err(format!(
+                "entered constructor {} (node {}) through an auxiliary port",
+                tag, id
+            )),
+            Node::Dup { .. } => unreachable!("follow() passes duplicators"),
+            Node::Era { .. } => Ok(Term::Era),
+            Node::Ref { name, .. } => Ok(Term::Ref(name.clone())),
+            Node::Root { .. } => Err("walked back into the root".to_string()),
+        }
+    }
+
+    // Follows the wire out of `from`, through any duplicators on the way,
+    // to the port of the first other node it enters. Done in a loop rather
+    // than by recursion, so a long chain of copies does not use up the stack.
+    fn follow(&mut self, mut from: Port, dups: &mut Exits) -> Result<Port, String> {
+        loop {
+            self.steps += 1;
+            if self.steps > MAX_READ_STEPS {
+                return Err("the net is too large or cyclic to read back".to_string());
+            }
+            if let Err(msg) = self.source.demand(from) {
+                self.halted = Some(msg.clone());
+                return Err(msg);
+            }
+
+            let net = self.source.net();
+            let at = net.port(from.node, from.slot);
+            if at.is_null() {
+                return Err("reached a free wire".to_string());
+            }
+            from = match net.get_node(at.node) {
+                Node::Dup { label, .. } if at.slot == 0 => {
+                    match dups.get_mut(label).and_then(|exits| exits.pop()) {
+                        Some(copy) => Port::new(at.node, copy),
+                        None => return Err(format!("entered duplicator {} from below", at.node)),
+                    }
+                }
+                Node::Dup { label, .. } => {
+                    dups.entry(*label).or_default().push(at.slot);
+                    Port::new(at.node, 0)
+                }
+                _ => return Ok(at),
+            };
+        }
+    }
+
+    // A name for the variable of a lambda node, the same however often it
+    // is met; fresh_names() gives the ones that are shown
+    fn name(&mut self, lambda: NodeId) -> String {
+        let next = self.names.len();
+        self.names
+            .entry(lambda)
+            .or_insert_with(|| nth_name(next))
+            .clone()
+    }
+}
+
+// a, b, ..., z, a1, b1, ...
+fn nth_name(n: usize) -> String {
+    let letter = (b'a' + (n % 26) as u8) as char;
+    match n / 26 {
+        0 => letter.to_string(),
+        round => format!("{}{}", letter, round),
+    }
+}
+
+// `term` with its bound variables renamed a, b, ... in the order they are
+// bound, leaving out the names its references and free variables go by
+pub fn fresh_names(term: &Term) -> Term {
+    let mut taken = HashSet::new();
+    free_names(term, &mut Vec::new(), &mut taken);
+    let mut renaming = Renaming {
+        taken,
+        next: 0,
+        scope: Vec::new(),
+    };
+    renaming.rename(term)
+}
+
+fn free_names<'a>(term: &'a Term, bound: &mut Vec<&'a str>, found: &mut HashSet<String>) {
+    match term {
+        Term::Var(name) if !bound.contains(&name.as_str()) => {
+            found.insert(name.clone());
+        }
+        Term::Ref(name) => {
+            found.insert(name.clone());
+        }
+        Term::Lam(var, body) => {
+            bound.push(var);
+            free_names(body, bound, found);
+            bound.pop();
+        }
+        Term::Dup(_, a, b, value, body) => {
+            free_names(value, bound, found);
+            bound.extend([a.as_str(), b.as_str()]);
+            free_names(body, bound, found);
+            bound.truncate(bound.len() - 2);
+        }
+        Term::App(a, b) | Term::Con(_, a, b) => {
+            free_names(a, bound, found);
+            free_names(b, bound, found);
+        }
+        Term::Var(_) | Term::Era | Term::Thunk => {}
+    }
+}
+
+struct Renaming {
+    taken: HashSet<String>,       // names that must not be given out
+    next: usize,                  // how far along nth_name() to look next
+    scope: Vec<(String, String)>, // old name to new, innermost last
+}
+
+impl Renaming {
+    fn fresh(&mut self) -> String {
+        loop {
+            let name = nth_name(self.next);
+            self.next += 1;
+            if !self.taken.contains(&name) {
+                return name;
+            }
+        }
+    }
+
+    // Renames `var` to a fresh name while `body` is read
+    fn under<T>(&mut self, var: &str, body: impl FnOnce(&mut Self) -> T) -> (String, T) {
+        let name = self.fresh();
+        self.scope.push((var.to_string(), name.clone()));
+        let body = body(self);
+        self.scope.pop();
+        (name, body)
+    }
+
+    fn rename(&mut self, term: &Term) -> Term {
+        match term {
+            Term::Var(name) => {
+                let renamed = self.scope.iter().rev().find(|(old, _)| old == name);
+                Term::Var(renamed.map_or(name, |(_, new)| new).clone())
+            }
+            Term::Lam(var, body) => {
+                let (var, body) = self.under(var, |renaming| renaming.rename(body));
+                Term::Lam(var, Box::new(body))
+            }
+            Term::App(fun, arg) => Term::app(self.rename(fun), self.rename(arg)),
+            Term::Con(tag, left, right) => Term::Con(
+                *tag,
+                Box::new(self.rename(left)),
+                Box::new(self.rename(right)),
+            ),
+            Term::Dup(label, a, b, value, body) => {
+                let value = self.rename(value);
+                let (a, (b, body)) = self.under(a, |renaming| {
+                    renaming.under(b, |renaming| renaming.rename(body))
+                });
+                Term::Dup(*label, a, b, Box::new(value), Box::new(body))
+            }
+            Term::Ref(_) | Term::Era | Term::Thunk => term.clone(),
+        }
+    }
+}
+
+// Decodes a term as the given shape; None if it does not have that shape
+pub fn decode(term: &Term, shape: &Shape, encoding: Encoding) -> Option<Value> {
+    match shape {
+        Shape::Number => decode_number(term, encoding).map(Value::Number),
+        Shape::Boolean => {
+            let (t, f, body) = binder2(term)?;
+            match body {
+                Term::Var(v) if v == t => Some(Value::Boolean(true)),
+                Term::Var(v) if v == f => Some(Value::Boolean(false)),
+                _ => None,
+            }
+        }
+        Shape::Tuple(shapes) => {
+            let Term::Lam(p, body) = term else {
+                return None;
+            };
+            let (head, args) = spine(body);
+            if *head != Term::Var(p.clone()) || args.len() != shapes.len() {
+                return None;
+            }
+            let items = args
+                .iter()
+                .zip(shapes)
+                .map(|(arg, shape)| decode(arg, shape, encoding))
+                .collect::<Option<Vec<_>>>()?;
+            Some(Value::Tuple(items))
+        }
+        Shape::List(item) => match decode_list(term, item, encoding)? {
+            (items, true) => Some(Value::List(items)),
+            (items, false) => Some(Value::Stream(items)),
+        },
+        Shape::Term => Some(Value::Term(term.clone())),
+    }
+}
+
+fn decode_number(term: &Term, encoding: Encoding) -> Option<u64> {
+    let (first, second, mut body) = binder2(term)?;
+    match encoding {
+        // λf λx (f (f ... x))
+        Encoding::Church => {
+            let mut n = 0;
+            loop {
+                match body {
+                    Term::Var(x) if x == second => return Some(n),
+                    Term::App(fun, arg) if **fun == Term::Var(first.clone()) => {
+                        n += 1;
+                        body = arg;
+                    }
+                    _ => return None,
+                }
+            }
+        }
+        // λz λs z  or  λz λs (s pred)
+        Encoding::Scott => match body {
+            Term::Var(z) if z == first => Some(0),
+            Term::App(fun, pred) if **fun == Term::Var(second.clone()) => {
+                decode_number(pred, encoding).map(|n| n + 1)
+            }
+            _ => None,
+        },
+    }
+}
+
+// The items of a list, and whether the whole list was there. A lazy read
+// cuts a stream off with a Thunk, which ends the list early instead of
+// failing it: at the tail, or inside the first item it reached too deep for.
+fn decode_list(term: &Term, item: &Shape, encoding: Encoding) -> Option<(Vec<Value>, bool)> {
+    let mut items = Vec::new();
+    if *term == Term::Thunk {
+        return Some((items, false));
+    }
+    let (cons, nil, mut body) = binder2(term)?;
+    loop {
+        match body {
+            Term::Var(n) if n == nil => return Some((items, true)),
+            Term::Thunk => return Some((items, false)),
+            _ => {
+                let (head, args) = spine(body);
+                let value = match args[..] {
+                    [value, _] if *head == Term::Var(cons.clone()) => value,
+                    _ if *head == Term::Thunk => return Some((items, false)),
+                    _ => return None,
+                };
+                // Only an item the read cut off ends the stream; one that is
+                // all there but not of the item's shape fails the list
+                match decode(value, item, encoding) {
+                    Some(value) => items.push(value),
+                    None if has_thunk(value) => return Some((items, false)),
+                    None => return None,
+                }
+                match encoding {
+                    // The tail is the rest of the same fold
+                    Encoding::Church => body = args[1],
+                    // The tail is a whole list of its own
+                    Encoding::Scott => {
+                        let (rest, complete) = decode_list(args[1], item, encoding)?;
+                        items.extend(rest);
+                        return Some((items, complete));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn has_thunk(term: &Term) -> bool {
+    match term {
+        Term::Thunk => true,
+        Term::Lam(_, body) => has_thunk(body),
+        Term::App(a, b) | Term::Con(_, a, b) | Term::Dup(_, _, _, a, b) => {
+            has_thunk(a) || has_thunk(b)
+        }
+        _ => false,
+    }
+}
+
+// Splits λa λb body into its two variables and the body
+fn binder2(term: &Term) -> Option<(&String, &String, &Term)> {
+    match term {
+        Term::Lam(a, inner) => match inner.as_ref() {
+            Term::Lam(b, body) => Some((a, b, body)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Splits ((f a) b) into f and [a, b]
+fn spine(term: &Term) -> (&Term, Vec<&Term>) {
+    let mut args = Vec::new();
+    let mut head = term;
+    while let Term::App(fun, arg) = head {
+        args.push(arg.as_ref());
+        head = fun;
+    }
+    args.reverse();
+    (head, args)
+}
+
+// Every node reachable from the root, one per line, for when nothing else works
+pub fn residual(net: &Net) -> String {
+    let Some(root) = net.root() else {
+        return format!("{:?}", net);
+    };
+
+    let mut seen = BTreeSet::from([root]);
+    let mut queue = VecDeque::from([root]);
+    while let Some(id) = queue.pop_front() {
+        for slot in 0..net.get_node(id).arity() {
+            let port = net.port(id, slot);
+            if !port.is_null() && seen.insert(port.node) {
+                queue.push_back(port.node);
+            }
+        }
+    }
+
+    seen.iter()
+        .map(|&id| format!("  {}: {}", id, net.node_str(id)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -1,8 +1,27 @@
 // || shree ganesh ||
 //
 
+use crate::analysis::{StuckTerm, find_stuck_terms, find_vicious_circles};
+use crate::breakpoint::Breakpoint;
+use crate::checkpoint;
+use crate::fuel::FuelCosts;
+use crate::host;
+use crate::interaction::Rule;
 use crate::interaction::apply_interaction_result as apply_interaction; //for testing purpose we are importing this apply_interaction_result
-use crate::net::{Net, NodeId};
+use crate::interaction::expand_ref;
+use crate::net::{Net, Node, NodeId, NodeKind, Port, Redex};
+use crate::observer::{InteractionEvent, Recorder, RuntimeObserver};
+use crate::parallel;
+use crate::profile::Profiler;
+use crate::trace::{TraceEntry, TraceWriter, read_trace};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 // it  is more a kind of execution engine while the net and interactions are the files stating the structure and the rules of the engine
 // the runtime here is reponsible for implementing the core evaluation strategies i.e. to finding and processing redexes until no more redexes exist
@@ -11,6 +30,135 @@ use crate::net::{Net, NodeId};
 pub struct Runtime {
     net: Net, //this is the net that is taken under evaluation
     stats: RuntimeStats,
+    debug: bool, // when set, the net is checked for stuck terms once it stops reducing
+    stuck: Vec<StuckTerm>, // what the last check found
+    threads: usize, // more than one switches run() to the parallel work-stealing engine
+    limits: RunLimits,
+    cancel: CancelToken,
+    lazy: bool, // only reduce what the root depends on, see whnf()
+    sample_every: Option<usize>,
+    observers: Vec<Box<dyn RuntimeObserver + Send>>,
+    breakpoints: Vec<Breakpoint>,
+    hit: Option<InteractionEvent>, // the interaction that stopped the last run at a breakpoint
+    fuel: Option<u64>,             // what is left in the tank, None when not metering
+    fuel_costs: FuelCosts,
+    sliced: (usize, Duration), // steps and time of the run_for() slices of the run so far
+}
+
+// Asked after every interaction whether to hand control back to the caller
+type StopWhen<'a> = Option<&'a mut dyn FnMut(&Net, &InteractionEvent) -> bool>;
+
+// Bounds on a single call to run(); None leaves that resource unbounded.
+// Hitting one stops the run between two interactions, so the net is always
+// whole and calling run() again simply carries on, with the steps and time
+// counted from zero again. The slices run_for() cuts a run into count
+// together, up to the slice that ends it.
+#[derive(Debug, Clone, Default)]
+pub struct RunLimits {
+    pub max_steps: Option<usize>,  // interactions applied by this call
+    pub max_nodes: Option<usize>,  // live nodes in the net
+    pub timeout: Option<Duration>, // wall-clock time spent in this call
+}
+
+impl RunLimits {
+    // Which limit, if any, stops a run that has done `steps` interactions since `start`
+    pub fn exceeded(&self, steps: usize, nodes: usize, start: Instant) -> Option<RunOutcome> {
+        if self.max_steps.is_some_and(|max| steps >= max) {
+            Some(RunOutcome::StepLimit)
+        } else if self.max_nodes.is_some_and(|max| nodes > max) {
+            Some(RunOutcome::NodeLimit)
+        } else if self.timeout.is_some_and(|max| start.elapsed() >= max) {
+            Some(RunOutcome::Timeout)
+        } else {
+            None
+        }
+    }
+}
+
+// Why run() returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    Normal,     // no redexes left
+    StepLimit,  // RunLimits::max_steps interactions were applied
+    NodeLimit,  // the net grew past RunLimits::max_nodes live nodes
+    Timeout,    // RunLimits::timeout elapsed
+    Cancelled,  // somebody called cancel() on the runtime's CancelToken
+    Breakpoint, // a breakpoint or the run_until() predicate asked to stop
+    OutOfFuel,  // the next interaction costs more fuel than is left
+}
+
+// How much work run_for() may do before handing control back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Budget {
+    Steps(usize),
+    Time(Duration),
+}
+
+// What a run_for() slice left behind
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    Pending,          // the budget ran out with work still to do
+    Done(RunOutcome), // the run ended on its own, see RunOutcome
+}
+
+impl Progress {
+    pub fn is_pending(&self) -> bool {
+        *self == Progress::Pending
+    }
+}
+
+// Drives a runtime to the end one budget-sized slice per poll, so an async
+// executor is never blocked for longer than a slice. The task wakes itself
+// after every slice to give other tasks a turn in between.
+pub struct RunFuture<'a> {
+    runtime: &'a mut Runtime,
+    budget: Budget,
+}
+
+impl Future for RunFuture<'_> {
+    type Output = Result<RunOutcome, String>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let result = this.runtime.poll_run(this.budget);
+        if result.is_pending() {
+            cx.waker().wake_by_ref();
+        }
+        result
+    }
+}
+
+// Lets another thread stop a running evaluation. All clones share one flag,
+// which run() checks before every interaction. The flag stays set until
+// reset(), so a cancelled runtime keeps returning Cancelled straight away.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    flag: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        self.flag.store(false, Ordering::SeqCst);
+    }
+}
+
+// One unit of work: a redex (already out of the queue), or a reference that
+// has to be unfolded to see what it is
+enum Action {
+    Redex(Redex),
+    Expand(NodeId),
 }
 
 /// Defining the RuntimeStats struct
@@ -18,6 +166,53 @@ pub struct Runtime {
 pub struct RuntimeStats {
     pub steps: usize, //this states how many interactions have been applied
     pub max_nodes: usize,
+    pub threads: usize,      // worker threads used by the last run
+    pub steals: usize,       // redexes a worker took from another worker's queue
+    pub speedup: f64,        // over one thread, estimated by the last parallel run
+    pub balance: f64,        // how evenly the threads shared the work, see ParallelReport
+    pub wall_time: Duration, // time spent inside run(), step() and whnf()
+    // Time spent applying rules, split between unfolding references and the
+    // other rewrites. Only the sequential and lazy loops measure these.
+    pub ref_time: Duration,
+    pub rewrite_time: Duration,
+    pub samples: Vec<Sample>, // see Runtime::set_sampling
+    pub fuel_used: u64,       // see Runtime::set_fuel
+}
+
+impl RuntimeStats {
+    // Millions of interactions per second of wall time
+    pub fn mips(&self) -> f64 {
+        let seconds = self.wall_time.as_secs_f64();
+        if seconds == 0.0 {
+            return 0.0;
+        }
+        self.steps as f64 / seconds / 1_000_000.0
+    }
+
+    pub fn samples_csv(&self) -> String {
+        let mut csv = String::from("step,live_nodes,queue_len\n");
+        for sample in &self.samples {
+            csv.push_str(&format!(
+                "{},{},{}\n",
+                sample.step, sample.live_nodes, sample.queue_len
+            ));
+        }
+        csv
+    }
+
+    pub fn write_samples_csv(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        let path = path.as_ref();
+        std::fs::write(path, self.samples_csv())
+            .map_err(|e| format!("Cannot write {}: {}", path.display(), e))
+    }
+}
+
+// The size of the net at one point of the run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    pub step: usize,
+    pub live_nodes: usize,
+    pub queue_len: usize, // active pairs waiting
 }
 
 impl Runtime {
@@ -26,44 +221,721 @@ impl Runtime {
         Self {
             net,
             stats: RuntimeStats::default(),
+            debug: cfg!(debug_assertions),
+            stuck: Vec::new(),
+            threads: 1,
+            limits: RunLimits::default(),
+            cancel: CancelToken::new(),
+            lazy: false,
+            sample_every: None,
+            observers: Vec::new(),
+            breakpoints: Vec::new(),
+            hit: None,
+            fuel: None,
+            fuel_costs: FuelCosts::default(),
+            sliced: (0, Duration::ZERO),
         }
     }
 
+    // A handle that stops run() from another thread
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    pub fn set_limits(&mut self, limits: RunLimits) {
+        self.limits = limits;
+    }
+
+    // Number of worker threads run() reduces with; 1 keeps the sequential loop
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    // Observers hear about every interaction applied by run(), step() and
+    // whnf(). They need to see each one in order, so with observers attached
+    // run() stays on the sequential loop whatever set_threads() says.
+    pub fn add_observer(&mut self, observer: impl RuntimeObserver + Send + 'static) {
+        self.observers.push(Box::new(observer));
+    }
+
+    pub fn clear_observers(&mut self) {
+        self.observers.clear();
+    }
+
+    // run() stops with RunOutcome::Breakpoint right after an interaction that
+    // hits one of these; calling run() again carries on from there
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.breakpoints.push(breakpoint);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    // The interaction that stopped the last run at a breakpoint
+    pub fn breakpoint_hit(&self) -> Option<&InteractionEvent> {
+        self.hit.as_ref()
+    }
+
+    // In lazy mode run() and step() leave alone every redex the root does not
+    // depend on, and stop once the root holds a value (weak head normal form).
+    // Readback can then ask for the sub-terms it wants, one at a time.
+    pub fn set_lazy(&mut self, lazy: bool) {
+        self.lazy = lazy;
+    }
+
+    // Records a Sample in the stats every `every` interactions (None turns it
+    // off). The parallel engine does not take samples.
+    pub fn set_sampling(&mut self, every: Option<usize>) {
+        self.sample_every = every.filter(|&every| every > 0);
+    }
+
+    // Meters every interaction against a tank of `fuel` (None stops metering).
+    // Running dry stops run() with RunOutcome::OutOfFuel before the
+    // interaction that could not be paid for; set_fuel() again to top up.
+    // Metered runs stay on one thread, which is what prices each interaction.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    pub fn fuel_left(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn set_fuel_costs(&mut self, costs: FuelCosts) {
+        self.fuel_costs = costs;
+    }
+
+    // Debug mode is on by default in debug builds
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
     //Theory: There's mut self inside the args as it will be mutating both the runtime.net and runtime.stats
-    pub fn run(&mut self) -> Result<(), String> {
-        self.stats = RuntimeStats::default();
-        self.stats.max_nodes = self.net.node_count();
+    // Stats keep adding up over several calls, so a run stopped by a limit can be resumed
+    pub fn run(&mut self) -> Result<RunOutcome, String> {
+        // A run of its own, whatever run_for() slices came before
+        self.sliced = (0, Duration::ZERO);
+        self.timed(|runtime| runtime.run_untimed(None))
+    }
+
+    // Like run(), but also stops (with RunOutcome::Breakpoint) as soon as the
+    // predicate returns true for the interaction just applied
+    pub fn run_until(
+        &mut self,
+        mut predicate: impl FnMut(&Net, &InteractionEvent) -> bool,
+    ) -> Result<RunOutcome, String> {
+        self.timed(|runtime| runtime.run_untimed(Some(&mut predicate)))
+    }
+
+    fn run_untimed(&mut self, mut stop: StopWhen) -> Result<RunOutcome, String> {
+        self.stats.max_nodes = self.stats.max_nodes.max(self.net.node_count());
+        self.hit = None;
+
+        if self.lazy {
+            let root = self.net.root().ok_or("Lazy evaluation needs a root")?;
+            return self.whnf_untimed(Port::new(root, 0), stop);
+        }
+        // Breakpoints and observers have to see the interactions one at a time
+        let watched = stop.is_some() || !self.breakpoints.is_empty() || !self.observers.is_empty();
+        // Host functions read their argument back, which the workers cannot
+        // do under their node locks
+        if self.threads > 1 && !watched && !self.net.has_hosts() && self.fuel.is_none() {
+            return self.run_parallel();
+        }
+        self.stats.threads = 1;
+        self.stats.speedup = 1.0;
+        self.stats.balance = 1.0;
+
+        let start = Instant::now();
+        let mut steps = 0;
 
         // Keep evaluating until no more redexes
-        while let Some(redex) = self.net.next_redex() {
+        while self.net.redex_count() > 0 {
+            // Limits are checked before taking the next redex, so it stays queued
+            let redex = *self.net.active_pairs().next().unwrap();
+            let prepared = self
+                .prepare(&Action::Redex(redex), &mut steps, start, &mut stop)
+                .map_err(|msg| format!("Error during evaluation: {}", msg))?;
+            if let Some(outcome) = prepared {
+                return Ok(outcome);
+            }
+            self.net.next_redex();
             // Apply the interaction rule
-            match apply_interaction(&mut self.net, redex) {
-                Ok(_) => {
-                    self.stats.steps += 1;
-                    self.stats.max_nodes = self.stats.max_nodes.max(self.net.node_count());
+            let hit = self
+                .fire_checked(Action::Redex(redex), &mut stop)
+                .map_err(|msg| format!("Error during evaluation: {}", msg))?;
+            steps += 1;
+            if hit {
+                return Ok(RunOutcome::Breakpoint);
+            }
+        }
+
+        self.finish()
+    }
+
+    // Same as run(), but the redexes are spread over several worker threads
+    fn run_parallel(&mut self) -> Result<RunOutcome, String> {
+        let report = parallel::reduce(&mut self.net, self.threads, &self.limits, &self.cancel)
+            .map_err(|msg| format!("Error during evaluation: {}", msg))?;
+
+        self.stats.steps += report.steps;
+        self.stats.max_nodes = self.stats.max_nodes.max(report.max_nodes);
+        self.stats.threads = self.threads;
+        self.stats.steals += report.steals;
+        self.stats.speedup = report.speedup;
+        self.stats.balance = report.balance;
+
+        if report.outcome == RunOutcome::Normal {
+            return self.finish();
+        }
+        Ok(report.outcome)
+    }
+
+    // Reduces the value on the other side of `from` to weak head normal form,
+    // firing only the redexes that value depends on
+    pub fn whnf(&mut self, from: Port) -> Result<RunOutcome, String> {
+        self.timed(|runtime| runtime.whnf_untimed(from, None))
+    }
+
+    fn whnf_untimed(&mut self, from: Port, mut stop: StopWhen) -> Result<RunOutcome, String> {
+        let start = Instant::now();
+        let mut steps = 0;
+
+        while let Some(demand) = self.next_demand(from) {
+            let prepared = self
+                .prepare(&demand, &mut steps, start, &mut stop)
+                .map_err(|msg| format!("Error during evaluation: {}", msg))?;
+            if let Some(outcome) = prepared {
+                return Ok(outcome);
+            }
+
+            self.dequeue(&demand);
+            let hit = self
+                .fire_checked(demand, &mut stop)
+                .map_err(|msg| format!("Error during evaluation: {}", msg))?;
+            steps += 1;
+            if hit {
+                return Ok(RunOutcome::Breakpoint);
+            }
+        }
+        Ok(RunOutcome::Normal)
+    }
+
+    // What stands between `from` and a value, if anything. The walk enters
+    // the node on the other side of the wire; a node entered through an
+    // auxiliary port gets its value from its principal port, so the walk
+    // leaves through that. It ends on the first principal port it reaches:
+    // if it got there through another principal port the two form the redex
+    // to fire, a reference still has to be unfolded, anything else is the value.
+    fn next_demand(&self, from: Port) -> Option<Action> {
+        let mut leaving = from;
+        // A vicious circle would keep the walk going forever
+        for _ in 0..=self.net.node_count() {
+            let at = self.net.port(leaving.node, leaving.slot);
+            if at.is_null() {
+                return None;
+            }
+            if at.slot == 0 {
+                let (a, b) = (leaving.node, at.node);
+                if leaving.slot == 0 && self.net.is_redex(a, b) {
+                    return Some(Action::Redex(Redex { a, b }));
                 }
-                Err(msg) => {
-                    return Err(format!("Error during evaluation: {}", msg));
+                if let Node::Ref { .. } = self.net.get_node(b) {
+                    return Some(Action::Expand(b));
                 }
+                return None;
             }
+            leaving = Port::new(at.node, 0);
         }
+        None
+    }
 
+    // The limit, cancellation or empty tank that keeps `action` from firing
+    fn stopped(&self, action: &Action, steps: usize, start: Instant) -> Option<RunOutcome> {
+        if self.cancel.is_cancelled() {
+            return Some(RunOutcome::Cancelled);
+        }
+        let nodes = self.net.node_count();
+        if let Some(outcome) = self.limits.exceeded(steps, nodes, start) {
+            return Some(outcome);
+        }
+        self.out_of_fuel(action).then_some(RunOutcome::OutOfFuel)
+    }
+
+    // Gets `action` ready to fire, or tells what stops it. A host function
+    // reads its argument back (host.rs), so a call to one first has its
+    // argument reduced to normal form here. Those interactions are steps
+    // like any other: limited, metered, watched and traced.
+    fn prepare(
+        &mut self,
+        action: &Action,
+        steps: &mut usize,
+        start: Instant,
+        stop: &mut StopWhen,
+    ) -> Result<Option<RunOutcome>, String> {
+        if let Some(outcome) = self.stopped(action, *steps, start) {
+            return Ok(Some(outcome));
+        }
+        let Some(arg) = self.host_argument(action) else {
+            return Ok(None);
+        };
+        let (mut pending, rest) = host::split_queue(&mut self.net, arg);
+        let result = self.reduce_argument(&mut pending, steps, start, stop);
+        let queued = self.net.take_redexes();
+        self.net
+            .queue_redexes(rest.into_iter().chain(pending).chain(queued));
+        match result? {
+            Some(outcome) => Ok(Some(outcome)),
+            None => Ok(self.stopped(action, *steps, start)),
+        }
+    }
+
+    // Fires the redexes of a host call's argument, and those they queue,
+    // until none are left or something stops the run. The queue stays
+    // empty meanwhile; what fires puts new redexes there, and only there.
+    fn reduce_argument(
+        &mut self,
+        pending: &mut VecDeque<Redex>,
+        steps: &mut usize,
+        start: Instant,
+        stop: &mut StopWhen,
+    ) -> Result<Option<RunOutcome>, String> {
+        while let Some(redex) = pending.pop_front() {
+            let action = Action::Redex(redex);
+            // A host call inside the argument looks for its own argument
+            // in the queue
+            if self.host_argument(&action).is_some() {
+                self.net.queue_redexes(pending.drain(..));
+            }
+            let prepared = self.prepare(&action, steps, start, stop);
+            pending.extend(self.net.take_redexes());
+            if let Some(outcome) = prepared? {
+                pending.push_front(redex);
+                return Ok(Some(outcome));
+            }
+
+            let hit = self.fire_checked(action, stop)?;
+            *steps += 1;
+            pending.extend(self.net.take_redexes());
+            if hit {
+                return Ok(Some(RunOutcome::Breakpoint));
+            }
+        }
+        Ok(None)
+    }
+
+    // The port of the argument, when the action calls a host function
+    fn host_argument(&self, action: &Action) -> Option<Port> {
+        match *action {
+            Action::Redex(redex) => {
+                host::argument(&self.net, redex, |name| self.net.host(name).is_some())
+            }
+            Action::Expand(_) => None,
+        }
+    }
+
+    // A redex picked out of order still sits in the queue; take it out so it
+    // does not fire twice
+    fn dequeue(&mut self, action: &Action) {
+        if let Action::Redex(redex) = action {
+            self.net.take_redex(redex.a, redex.b);
+        }
+    }
+
+    // Applies one piece of work and keeps the stats and observers up to date
+    fn fire(&mut self, action: Action) -> Result<(), String> {
+        if !self.observers.is_empty() {
+            return self.fire_recorded(action).map(|_| ());
+        }
+
+        let expands = self.expands(&action);
+        let cost = self.cost(&action);
+        let start = Instant::now();
+        match action {
+            Action::Redex(redex) => apply_interaction(&mut self.net, redex)?,
+            Action::Expand(id) => expand_ref(&mut self.net, id)?,
+        }
+        self.count_step(expands, start.elapsed(), cost);
         Ok(())
     }
 
+    // Same as fire(), but also tells whether a breakpoint or the predicate
+    // wants control back
+    fn fire_checked(&mut self, action: Action, stop: &mut StopWhen) -> Result<bool, String> {
+        if stop.is_none() && self.breakpoints.is_empty() {
+            return self.fire(action).map(|_| false);
+        }
+
+        let event = self.fire_recorded(action)?;
+        let hit = self.breakpoints.iter().any(|b| b.hit(&self.net, &event))
+            || stop
+                .as_mut()
+                .is_some_and(|predicate| predicate(&self.net, &event));
+        if hit {
+            self.hit = Some(event);
+        }
+        Ok(hit)
+    }
+
+    // Same as fire(), but also works out what the interaction changed
+    fn fire_recorded(&mut self, action: Action) -> Result<InteractionEvent, String> {
+        let redex = match action {
+            Action::Redex(redex) => redex,
+            Action::Expand(id) => Redex {
+                a: id,
+                b: self.net.port(id, 0).node,
+            },
+        };
+        let kinds = [
+            self.net.get_node(redex.a).kind(),
+            self.net.get_node(redex.b).kind(),
+        ];
+        for observer in &mut self.observers {
+            observer.before_interaction(&self.net, redex, &kinds);
+        }
+
+        let expands = self.expands(&action);
+        let cost = self.cost(&action);
+        let start = Instant::now();
+        let mut recorder = Recorder::new(&mut self.net);
+        match action {
+            Action::Redex(redex) => apply_interaction(&mut recorder, redex)?,
+            Action::Expand(id) => expand_ref(&mut recorder, id)?,
+        }
+        let spent = start.elapsed();
+        let event = InteractionEvent {
+            redex,
+            kinds,
+            created: recorder.created,
+            freed: recorder.freed,
+            wires: recorder.wires,
+        };
+        for observer in &mut self.observers {
+            observer.after_interaction(&self.net, &event);
+        }
+
+        self.count_step(expands, spent, cost);
+        Ok(event)
+    }
+
+    // Whether the action unfolds a reference rather than rewriting agents
+    fn expands(&self, action: &Action) -> bool {
+        match action {
+            Action::Redex(redex) => [redex.a, redex.b]
+                .iter()
+                .any(|&id| matches!(self.net.get_node(id), Node::Ref { .. })),
+            Action::Expand(_) => true,
+        }
+    }
+
+    // The fuel the action burns, or 0 when not metering
+    fn cost(&self, action: &Action) -> u64 {
+        if self.fuel.is_none() {
+            return 0;
+        }
+        match *action {
+            Action::Redex(redex) => self.fuel_costs.price(&self.net, redex.a, redex.b),
+            Action::Expand(id) => {
+                let peer = self.net.port(id, 0).node;
+                self.fuel_costs.price(&self.net, id, peer)
+            }
+        }
+    }
+
+    fn out_of_fuel(&self, action: &Action) -> bool {
+        self.fuel.is_some_and(|left| self.cost(action) > left)
+    }
+
+    fn count_step(&mut self, expanded: bool, spent: Duration, cost: u64) {
+        if expanded {
+            self.stats.ref_time += spent;
+        } else {
+            self.stats.rewrite_time += spent;
+        }
+        // step() does not check the tank first, so it may overdraw it
+        if let Some(left) = self.fuel.as_mut() {
+            *left = left.saturating_sub(cost);
+            self.stats.fuel_used += cost;
+        }
+        self.stats.steps += 1;
+        self.stats.max_nodes = self.stats.max_nodes.max(self.net.node_count());
+
+        if self
+            .sample_every
+            .is_some_and(|every| self.stats.steps.is_multiple_of(every))
+        {
+            self.stats.samples.push(Sample {
+                step: self.stats.steps,
+                live_nodes: self.net.node_count(),
+                queue_len: self.net.redex_count(),
+            });
+        }
+    }
+
+    // Runs `f` and adds the time it took to the wall time
+    fn timed<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        let start = Instant::now();
+        let result = f(self);
+        self.stats.wall_time += start.elapsed();
+        result
+    }
+
+    // Saves everything the runtime needs to carry on later, possibly in
+    // another process (see checkpoint.rs)
+    pub fn checkpoint(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        checkpoint::save(&self.net, &self.stats, path)
+    }
+
+    // A runtime that carries on from a checkpoint exactly where the saved one
+    // stopped. Settings (threads, limits, lazy mode, observers) are not part
+    // of the checkpoint, so set them again before calling run(). A net that
+    // calls host functions has to be resumed with resume_with().
+    pub fn resume(path: impl AsRef<Path>) -> Result<Runtime, String> {
+        Self::resume_with(path, |_| Ok(()))
+    }
+
+    // Like resume(), with `restore` run on the loaded net first to register
+    // its host functions again (and set its source map, if wanted). Fails if
+    // a host function the saved net had is still missing afterwards.
+    pub fn resume_with(
+        path: impl AsRef<Path>,
+        restore: impl FnOnce(&mut Net) -> Result<(), String>,
+    ) -> Result<Runtime, String> {
+        let path = path.as_ref();
+        let (mut net, stats, hosts) = checkpoint::load(path)?;
+        restore(&mut net)?;
+        if let Some(name) = hosts.iter().find(|name| net.host(name).is_none()) {
+            return Err(format!(
+                "{}: host function {} is not registered; register it in resume_with()",
+                path.display(),
+                name
+            ));
+        }
+        let mut runtime = Runtime::new(net);
+        runtime.stats = stats;
+        Ok(runtime)
+    }
+
+    // Writes every interaction from now on to a trace file (see trace.rs).
+    // A write that fails does not stop the run; ask the handle for its error().
+    pub fn record_trace(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<Arc<Mutex<TraceWriter>>, String> {
+        let writer = Arc::new(Mutex::new(TraceWriter::create(path)?));
+        self.add_observer(writer.clone());
+        Ok(writer)
+    }
+
+    // Attributes every interaction from now on to a definition (see
+    // profile.rs). The handle stays readable while the runtime runs.
+    pub fn start_profiling(&mut self) -> Arc<Mutex<Profiler>> {
+        let profiler = Arc::new(Mutex::new(Profiler::new()));
+        self.add_observer(profiler.clone());
+        profiler
+    }
+
+    // Reapplies a trace written by record_trace() to this runtime's net, which
+    // has to be the net the recording started from, host functions and all.
+    // Refuses a trace with host calls in it. Stops at the first step
+    // that does not match what the trace says; returns the steps replayed.
+    pub fn replay(&mut self, path: impl AsRef<Path>) -> Result<usize, String> {
+        let entries = read_trace(path)?;
+        // A host function need not answer the same way twice, and a trace
+        // does not keep its answers, so replaying a call could only diverge
+        let host_call = entries.iter().find_map(|entry| {
+            entry.kinds.iter().find_map(|kind| match kind {
+                NodeKind::Ref(name) if self.net.host(name).is_some() => Some((entry.step, name)),
+                _ => None,
+            })
+        });
+        if let Some((step, name)) = host_call {
+            return Err(format!(
+                "Cannot replay: step {} calls host function {}, whose answers are not in the trace",
+                step, name
+            ));
+        }
+        for entry in &entries {
+            let diverged = |msg: String| format!("Replay diverged at step {}: {}", entry.step, msg);
+            let action = self.replay_action(entry).map_err(diverged)?;
+            self.dequeue(&action);
+            let event = self.fire_recorded(action).map_err(diverged)?;
+            if event.created != entry.created {
+                return Err(diverged(format!(
+                    "expected nodes {:?} to be created, got {:?}",
+                    entry.created, event.created
+                )));
+            }
+        }
+        Ok(entries.len())
+    }
+
+    // Checks that the net is in the state the trace entry was recorded in
+    fn replay_action(&self, entry: &TraceEntry) -> Result<Action, String> {
+        let Redex { a, b } = entry.redex;
+        if !self.net.is_live(a) || !self.net.is_live(b) {
+            return Err(format!("node {} or {} no longer exists", a, b));
+        }
+        let kinds = [self.net.get_node(a).kind(), self.net.get_node(b).kind()];
+        if kinds != entry.kinds {
+            return Err(format!(
+                "expected {} <-> {}, found {} <-> {}",
+                entry.kinds[0], entry.kinds[1], kinds[0], kinds[1]
+            ));
+        }
+        let peer = self.net.port(a, 0);
+        if peer.node != b {
+            return Err(format!("node {} is not plugged into node {}", a, b));
+        }
+        if Rule::between(&kinds[0], &kinds[1]) != Some(entry.rule) {
+            return Err(format!(
+                "{} does not apply to {} <-> {}",
+                entry.rule, kinds[0], kinds[1]
+            ));
+        }
+
+        if peer.slot == 0 && self.net.is_redex(a, b) {
+            Ok(Action::Redex(entry.redex))
+        } else if let NodeKind::Ref(_) = kinds[0] {
+            Ok(Action::Expand(a))
+        } else {
+            Err(format!("nodes {} and {} are not an active pair", a, b))
+        }
+    }
+
     // Run a single step of evaluation
     pub fn step(&mut self) -> Result<bool, String> {
-        if let Some(redex) = self.net.next_redex() {
-            match apply_interaction(&mut self.net, redex) {
-                Ok(_) => {
-                    self.stats.steps += 1;
-                    self.stats.max_nodes = self.stats.max_nodes.max(self.net.node_count());
-                    Ok(true) // More steps might be possible
-                }
-                Err(msg) => Err(format!("Error during step: {}", msg)),
-            }
+        self.timed(Self::step_untimed)
+    }
+
+    fn step_untimed(&mut self) -> Result<bool, String> {
+        let next = if self.lazy {
+            let root = self.net.root().ok_or("Lazy evaluation needs a root")?;
+            self.next_demand(Port::new(root, 0))
         } else {
-            Ok(false) // No more steps possible
+            self.net
+                .active_pairs()
+                .next()
+                .map(|&redex| Action::Redex(redex))
+        };
+        let Some(action) = next else {
+            if !self.lazy {
+                self.check_stuck();
+            }
+            return Ok(false); // No more steps possible
+        };
+
+        let action = match action {
+            Action::Redex(redex) => Action::Redex(self.argument_step(redex)?),
+            expand => expand,
+        };
+        self.dequeue(&action);
+        match self.fire(action) {
+            Ok(_) => Ok(true), // More steps might be possible
+            Err(msg) => Err(format!("Error during step: {}", msg)),
+        }
+    }
+
+    // What a step has to fire instead of `redex`: when that calls a host
+    // function whose argument is not reduced yet, the first interaction the
+    // argument needs
+    fn argument_step(&mut self, redex: Redex) -> Result<Redex, String> {
+        let is_host = |net: &Net, name: &str| net.host(name).is_some();
+        let first = host::reduce_argument(&mut self.net, redex, &is_host, &mut |_, first| {
+            Ok(Some(first))
+        })?;
+        Ok(first.unwrap_or(redex))
+    }
+
+    // Ends a run that found no more redexes. A vicious circle means the net
+    // is not the value of anything, as a correct term never builds one; the
+    // usual cause is a term that copies a copy of itself (see linear.rs).
+    fn finish(&mut self) -> Result<RunOutcome, String> {
+        self.check_stuck();
+        match find_vicious_circles(&self.net).first() {
+            Some(circle) => Err(format!("Evaluation ended in a {}", circle)),
+            None => Ok(RunOutcome::Normal),
+        }
+    }
+
+    // Runs the stuck-term analysis when in debug mode
+    fn check_stuck(&mut self) {
+        if self.debug {
+            self.stuck = find_stuck_terms(&self.net);
+        }
+    }
+
+    // Does at most one budget's worth of work. Limits set with set_limits()
+    // still apply, to all the slices of the run together; a slice they cut
+    // short reports their outcome as Done, and the next one starts a new run.
+    pub fn run_for(&mut self, budget: Budget) -> Result<Progress, String> {
+        // A zero step budget would never get anywhere
+        let budget = match budget {
+            Budget::Steps(steps) => Budget::Steps(steps.max(1)),
+            time => time,
+        };
+        let limits = self.limits.clone();
+        // What the earlier slices left of the limits
+        let (steps_done, time_spent) = self.sliced;
+        let mut slice = RunLimits {
+            max_steps: limits.max_steps.map(|max| max.saturating_sub(steps_done)),
+            timeout: limits.timeout.map(|max| max.saturating_sub(time_spent)),
+            ..limits.clone()
+        };
+        // Whether the budget is tighter than what is left
+        let binds = match budget {
+            Budget::Steps(steps) => {
+                let left = slice.max_steps;
+                slice.max_steps = Some(left.map_or(steps, |left| left.min(steps)));
+                left.is_none_or(|left| steps < left)
+            }
+            Budget::Time(time) => {
+                let left = slice.timeout;
+                slice.timeout = Some(left.map_or(time, |left| left.min(time)));
+                left.is_none_or(|left| time < left)
+            }
+        };
+
+        let steps_before = self.stats.steps;
+        let start = Instant::now();
+        self.limits = slice;
+        let result = self.timed(|runtime| runtime.run_untimed(None));
+        self.limits = limits;
+        self.sliced = (
+            steps_done + (self.stats.steps - steps_before),
+            time_spent + start.elapsed(),
+        );
+
+        let progress = match (result, budget) {
+            (Ok(RunOutcome::StepLimit), Budget::Steps(_))
+            | (Ok(RunOutcome::Timeout), Budget::Time(_))
+                if binds =>
+            {
+                Ok(Progress::Pending)
+            }
+            (outcome, _) => outcome.map(Progress::Done),
+        };
+        if !matches!(progress, Ok(Progress::Pending)) {
+            self.sliced = (0, Duration::ZERO);
+        }
+        progress
+    }
+
+    // Poll-style wrapper around run_for(): Pending while work remains
+    pub fn poll_run(&mut self, budget: Budget) -> Poll<Result<RunOutcome, String>> {
+        match self.run_for(budget) {
+            Ok(Progress::Pending) => Poll::Pending,
+            Ok(Progress::Done(outcome)) => Poll::Ready(Ok(outcome)),
+            Err(msg) => Poll::Ready(Err(msg)),
+        }
+    }
+
+    // The whole run as a future that gives the executor back every budget
+    pub fn run_async(&mut self, budget: Budget) -> RunFuture<'_> {
+        RunFuture {
+            runtime: self,
+            budget,
         }
     }
 
@@ -81,4 +953,9 @@ impl Runtime {
     pub fn get_stats(&self) -> &RuntimeStats {
         &self.stats
     }
+
+    // Stuck terms found when the last run finished (always empty outside debug mode)
+    pub fn get_stuck_terms(&self) -> &[StuckTerm] {
+        &self.stuck
+    }
 }
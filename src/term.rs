@@ -0,0 +1,219 @@
+// || shree ganesh ||
+//
+// Lambda terms, the shape results take once they are read back out of a net.
+//
+// The encoding used throughout: a lambda and an application are the same
+// agent, a Con node tagged LAMBDA_TAG, and differ only in which port faces up.
+//
+// Lambda:      principal = the term, aux 1 = the bound variable, aux 2 = the body
+// Application: principal = the function, aux 1 = the argument, aux 2 = the result
+//
+// so beta reduction is just the annihilation of two LAMBDA_TAG constructors.
+// A variable used several times hangs off a chain of Dup nodes sharing a
+// fresh label, and a variable that is never used is plugged into an Era.
+// `dup #l{a b} = v; body` (Term::Dup) puts such a node in by hand, with the
+// label it is given: the linearization pass in linear.rs writes its sharing
+// out that way, so every variable it leaves is used exactly once.
+
+use crate::lambda;
+use crate::net::{Net, Node, NodeId, Port};
+use std::collections::HashMap;
+use std::fmt;
+
+pub const LAMBDA_TAG: i32 = 0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Term {
+    Var(String),
+    Lam(String, Box<Term>),
+    App(Box<Term>, Box<Term>),
+    Con(i32, Box<Term>, Box<Term>), // a constructor with any other tag, read as data
+    Dup(u32, String, String, Box<Term>, Box<Term>), // dup #label{a b} = value; body
+    Ref(String),                    // a reference that was never expanded
+    Era,                            // an erased value
+    Thunk,                          // a sub-term lazy readback was not asked to evaluate
+}
+
+impl Term {
+    pub fn lam(var: &str, body: Term) -> Term {
+        Term::Lam(var.to_string(), Box::new(body))
+    }
+
+    pub fn app(fun: Term, arg: Term) -> Term {
+        Term::App(Box::new(fun), Box::new(arg))
+    }
+
+    pub fn var(name: &str) -> Term {
+        Term::Var(name.to_string())
+    }
+}
+
+// In the notation of the lambda frontend (see lambda::pretty), so that a
+// term quoted in an error message reads the way it would be written
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", lambda::pretty(self))
+    }
+}
+
+// Builds the net for a closed term and returns the port that carries its
+// value, left unplugged for the caller to connect
+pub fn encode(net: &mut Net, term: &Term) -> Result<Port, String> {
+    // Labels written into the term are taken before any fresh one is handed out
+    claim_labels(net, term)?;
+    // A placeholder to build into, dropped once the term hangs off it
+    let hole = net.create_node(Node::Root { port: Port::null() });
+    let built = Encoder::default().build(net, term, Port::new(hole, 0));
+    let value = net.port(hole, 0);
+    if !value.is_null() {
+        net.disconnect(value.node, value.slot);
+        // A bare reference would otherwise stay queued against the placeholder
+        net.take_redex(hole, value.node);
+    }
+    net.free_node(hole);
+    built.map(|_| value)
+}
+
+#[derive(Default)]
+struct Encoder {
+    uses: HashMap<String, Vec<Port>>, // where each bound variable is plugged in so far
+}
+
+impl Encoder {
+    // Builds `term` with its value plugged into `dest`
+    fn build(&mut self, net: &mut Net, term: &Term, dest: Port) -> Result<(), String> {
+        match term {
+            Term::Var(name) => {
+                let uses = self
+                    .uses
+                    .get_mut(name)
+                    .ok_or_else(|| format!("unbound variable {}", name))?;
+                uses.push(dest);
+            }
+            Term::Lam(var, body) => {
+                let lam = plug(net, lambda(), dest);
+                self.bind(net, var, Port::new(lam, 1), |encoder, net| {
+                    encoder.build(net, body, Port::new(lam, 2))
+                })?;
+            }
+            Term::App(fun, arg) => {
+                let app = net.create_node(lambda());
+                connect(net, Port::new(app, 2), dest);
+                self.build(net, arg, Port::new(app, 1))?;
+                self.build(net, fun, Port::new(app, 0))?;
+            }
+            Term::Con(tag, left, right) => {
+                let con = Node::Con {
+                    tag: *tag,
+                    ports: [Port::null(); 3],
+                };
+                let con = plug(net, con, dest);
+                self.build(net, left, Port::new(con, 1))?;
+                self.build(net, right, Port::new(con, 2))?;
+            }
+            Term::Dup(label, a, b, value, body) => {
+                let dup = net.create_node(Node::Dup {
+                    label: *label,
+                    ports: [Port::null(); 3],
+                });
+                self.build(net, value, Port::new(dup, 0))?;
+                self.bind(net, a, Port::new(dup, 1), |encoder, net| {
+                    encoder.bind(net, b, Port::new(dup, 2), |encoder, net| {
+                        encoder.build(net, body, dest)
+                    })
+                })?;
+            }
+            Term::Ref(name) => {
+                let node = Node::Ref {
+                    name: name.clone(),
+                    port: Port::null(),
+                };
+                plug(net, node, dest);
+            }
+            Term::Era => {
+                plug(net, Node::Era { port: Port::null() }, dest);
+            }
+            Term::Thunk => return Err("cannot encode an unevaluated sub-term".to_string()),
+        }
+        Ok(())
+    }
+
+    // Runs `scope` with `var` bound to the value on `var_port`, then hands
+    // that value to the uses the scope made of it
+    fn bind(
+        &mut self,
+        net: &mut Net,
+        var: &str,
+        var_port: Port,
+        scope: impl FnOnce(&mut Self, &mut Net) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let outer = self.uses.insert(var.to_string(), Vec::new());
+        let result = scope(self, net);
+        let uses = match outer {
+            Some(outer) => self.uses.insert(var.to_string(), outer),
+            None => self.uses.remove(var),
+        };
+        result?;
+        share(net, var_port, &uses.unwrap_or_default());
+        Ok(())
+    }
+}
+
+fn claim_labels(net: &mut Net, term: &Term) -> Result<(), String> {
+    match term {
+        Term::Dup(label, _, _, value, body) => {
+            net.claim_label(*label)?;
+            claim_labels(net, value)?;
+            claim_labels(net, body)
+        }
+        Term::Lam(_, body) => claim_labels(net, body),
+        Term::App(a, b) | Term::Con(_, a, b) => {
+            claim_labels(net, a)?;
+            claim_labels(net, b)
+        }
+        Term::Var(_) | Term::Ref(_) | Term::Era | Term::Thunk => Ok(()),
+    }
+}
+
+fn lambda() -> Node {
+    Node::Con {
+        tag: LAMBDA_TAG,
+        ports: [Port::null(); 3],
+    }
+}
+
+// Creates `node` with its principal port plugged into `dest`
+fn plug(net: &mut Net, node: Node, dest: Port) -> NodeId {
+    let id = net.create_node(node);
+    connect(net, Port::new(id, 0), dest);
+    id
+}
+
+fn connect(net: &mut Net, a: Port, b: Port) {
+    net.connect(a.node, a.slot, b.node, b.slot);
+}
+
+// Hands the value on `var` to every use: an eraser when there is none, a
+// chain of duplicators when there are several, all with the one label
+fn share(net: &mut Net, var: Port, uses: &[Port]) {
+    let Some((last, rest)) = uses.split_last() else {
+        plug(net, Node::Era { port: Port::null() }, var);
+        return;
+    };
+    let label = if rest.is_empty() {
+        0
+    } else {
+        net.fresh_label()
+    };
+    let mut source = var;
+    for &dest in rest {
+        let dup = net.create_node(Node::Dup {
+            label,
+            ports: [Port::null(); 3],
+        });
+        connect(net, Port::new(dup, 0), source);
+        connect(net, Port::new(dup, 1), dest);
+        source = Port::new(dup, 2);
+    }
+    connect(net, source, *last);
+}
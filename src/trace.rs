@@ -0,0 +1,146 @@
+// || shree ganesh ||
+//
+// The flight recorder of the engine. A TraceWriter is an observer that appends
+// one line per interaction to a file, flushed as it goes, so the trace
+// survives a crash. Runtime::replay() reapplies such a trace to the initial
+// net and checks that every step meets the same nodes, applies the same rule
+// and allocates the same node ids, so a run that misbehaved somewhere else
+// can be reproduced exactly. Host functions (host.rs) are the exception:
+// their answers are not recorded, so a trace that calls one is refused.
+//
+// One line per interaction:
+//
+//   <step> <a> <b> <rule> <kind of a> <kind of b> <created ids, comma separated, or ->
+//
+//   1 0 3 commute CON(100) CON(2) 4,5,6,7
+//
+// Lines starting with # are comments.
+
+use crate::interaction::Rule;
+use crate::net::{Net, NodeId, NodeKind, Redex};
+use crate::observer::{InteractionEvent, RuntimeObserver};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub step: usize,
+    pub redex: Redex,
+    pub rule: Rule,
+    pub kinds: [NodeKind; 2],
+    pub created: Vec<NodeId>,
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let created = if self.created.is_empty() {
+            "-".to_string()
+        } else {
+            let ids: Vec<String> = self.created.iter().map(|id| id.to_string()).collect();
+            ids.join(",")
+        };
+        write!(
+            f,
+            "{} {} {} {} {} {} {}",
+            self.step, self.redex.a, self.redex.b, self.rule, self.kinds[0], self.kinds[1], created
+        )
+    }
+}
+
+impl TraceEntry {
+    pub fn parse(line: &str) -> Result<TraceEntry, String> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [step, a, b, rule, kind_a, kind_b, created] = fields[..] else {
+            return Err(format!("Expected 7 fields, found {}", fields.len()));
+        };
+        let number = |field: &str| {
+            field
+                .parse::<usize>()
+                .map_err(|_| format!("Bad number: {}", field))
+        };
+
+        let created = match created {
+            "-" => Vec::new(),
+            ids => ids.split(',').map(number).collect::<Result<_, _>>()?,
+        };
+        Ok(TraceEntry {
+            step: number(step)?,
+            redex: Redex {
+                a: number(a)?,
+                b: number(b)?,
+            },
+            rule: rule.parse()?,
+            kinds: [kind_a.parse()?, kind_b.parse()?],
+            created,
+        })
+    }
+}
+
+// Records every interaction the runtime it is attached to applies
+pub struct TraceWriter {
+    out: LineWriter<File>,
+    steps: usize,
+    error: Option<String>, // the first write that failed; nothing is written after it
+}
+
+impl TraceWriter {
+    pub fn create(path: impl AsRef<Path>) -> Result<TraceWriter, String> {
+        let path = path.as_ref();
+        let file = File::create(path)
+            .map_err(|e| format!("Cannot create trace {}: {}", path.display(), e))?;
+        let mut out = LineWriter::new(file);
+        writeln!(out, "# vice trace: step a b rule kind_a kind_b created")
+            .map_err(|e| format!("Cannot write trace: {}", e))?;
+        Ok(TraceWriter {
+            out,
+            steps: 0,
+            error: None,
+        })
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+}
+
+impl RuntimeObserver for TraceWriter {
+    fn after_interaction(&mut self, _net: &Net, event: &InteractionEvent) {
+        if self.error.is_some() {
+            return;
+        }
+        self.steps += 1;
+        let [a, b] = &event.kinds;
+        let entry = TraceEntry {
+            step: self.steps,
+            redex: event.redex,
+            // The runtime only applies pairs that have a rule
+            rule: Rule::between(a, b).unwrap_or(Rule::Expand),
+            kinds: event.kinds.clone(),
+            created: event.created.clone(),
+        };
+        if let Err(e) = writeln!(self.out, "{}", entry) {
+            self.error = Some(format!("Cannot write trace: {}", e));
+        }
+    }
+}
+
+pub fn read_trace(path: impl AsRef<Path>) -> Result<Vec<TraceEntry>, String> {
+    let path = path.as_ref();
+    let file =
+        File::open(path).map_err(|e| format!("Cannot open trace {}: {}", path.display(), e))?;
+
+    let mut entries = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("Cannot read trace: {}", e))?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let entry = TraceEntry::parse(line)
+            .map_err(|msg| format!("{}:{}: {}", path.display(), number + 1, msg))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
@@ -0,0 +1,255 @@
+// || shree ganesh ||
+//
+// Interaction nets are confluent: whatever order the redexes fire in, the
+// normal form is the same net (up to how the nodes are numbered). This file
+// checks that empirically. The same program is reduced under several
+// schedules (sequential, parallel, random orders) and the final nets are
+// compared up to isomorphism, reporting the first place they differ.
+//
+// A divergence means either a race in the parallel engine or a rule in
+// interaction.rs that breaks confluence.
+
+use crate::interaction::apply_interaction_result as apply_interaction;
+use crate::net::{Net, NodeId, NodeKind};
+use crate::runtime::{RunLimits, RunOutcome, Runtime};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::time::Instant;
+
+// An order to fire the redexes in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    Sequential,      // the runtime's queue order
+    Parallel(usize), // the work-stealing engine with this many threads
+    Random(u64),     // a redex picked at random every step, from this seed
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Schedule::Sequential => write!(f, "sequential"),
+            Schedule::Parallel(threads) => write!(f, "parallel({})", threads),
+            Schedule::Random(seed) => write!(f, "random({})", seed),
+        }
+    }
+}
+
+// Reduces a copy of the net under every schedule and checks that each normal
+// form is isomorphic to the first one. The limits guard against programs
+// that do not terminate; a schedule that hits one is reported as an error.
+pub fn verify(net: &Net, schedules: &[Schedule], limits: &RunLimits) -> Result<(), String> {
+    let Some((first, rest)) = schedules.split_first() else {
+        return Ok(());
+    };
+    let expected = normalize(net, *first, limits)?;
+    for schedule in rest {
+        let actual = normalize(net, *schedule, limits)?;
+        if let Some(divergence) = compare(&expected, &actual) {
+            return Err(format!(
+                "{} and {} diverge: {}",
+                first, schedule, divergence
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub fn normalize(net: &Net, schedule: Schedule, limits: &RunLimits) -> Result<Net, String> {
+    let failed = |msg: String| format!("{}: {}", schedule, msg);
+    let outcome = match schedule {
+        Schedule::Sequential | Schedule::Parallel(_) => {
+            let mut runtime = Runtime::new(net.clone());
+            runtime.set_debug(false);
+            runtime.set_limits(limits.clone());
+            if let Schedule::Parallel(threads) = schedule {
+                runtime.set_threads(threads);
+            }
+            let outcome = runtime.run().map_err(failed)?;
+            let net = std::mem::take(runtime.get_net_mut());
+            (outcome, net)
+        }
+        Schedule::Random(seed) => {
+            let mut net = net.clone();
+            let outcome = run_random(&mut net, seed, limits).map_err(failed)?;
+            (outcome, net)
+        }
+    };
+
+    match outcome {
+        (RunOutcome::Normal, net) => Ok(net),
+        (outcome, _) => Err(failed(format!("stopped before normal form: {:?}", outcome))),
+    }
+}
+
+fn run_random(net: &mut Net, seed: u64, limits: &RunLimits) -> Result<RunOutcome, String> {
+    let mut rng = XorShift(seed.max(1));
+    let start = Instant::now();
+    let mut steps = 0;
+    while net.redex_count() > 0 {
+        if let Some(outcome) = limits.exceeded(steps, net.node_count(), start) {
+            return Ok(outcome);
+        }
+        let pick = (rng.next() % net.redex_count() as u64) as usize;
+        let redex = *net.active_pairs().nth(pick).unwrap();
+        net.take_redex(redex.a, redex.b);
+        apply_interaction(net, redex)?;
+        steps += 1;
+    }
+    Ok(RunOutcome::Normal)
+}
+
+// Small and good enough to shuffle redexes; no need for a dependency
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+// The first difference between two nets, or None when they are isomorphic.
+// The part hanging off the root is walked in step, so a difference there is
+// reported with the path that leads to it (the slots taken from the root).
+// Whatever is left is compared as a collection of disconnected pieces.
+//
+// Dup labels only matter in telling duplicators apart, and fresh ones are
+// handed out in whatever order the references unfold, so they are matched
+// up to renaming: one-to-one, like the nodes. Each disconnected piece
+// numbers its labels by itself.
+pub fn compare(a: &Net, b: &Net) -> Option<String> {
+    let mut a_to_b: HashMap<NodeId, NodeId> = HashMap::new();
+    let mut b_to_a: HashMap<NodeId, NodeId> = HashMap::new();
+    let mut labels: HashMap<u32, u32> = HashMap::new();
+    let mut labels_back: HashMap<u32, u32> = HashMap::new();
+    let mut queue = VecDeque::new();
+
+    match (a.root(), b.root()) {
+        (Some(x), Some(y)) => {
+            a_to_b.insert(x, y);
+            b_to_a.insert(y, x);
+            queue.push_back((x, y, "root".to_string()));
+        }
+        (None, None) => {}
+        _ => return Some("only one of the nets has a root".to_string()),
+    }
+
+    while let Some((x, y, path)) = queue.pop_front() {
+        let (kx, ky) = (a.get_node(x).kind(), b.get_node(y).kind());
+        let same = match (&kx, &ky) {
+            (NodeKind::Dup(l), NodeKind::Dup(m)) => {
+                *labels.entry(*l).or_insert(*m) == *m && *labels_back.entry(*m).or_insert(*l) == *l
+            }
+            _ => kx == ky,
+        };
+        if !same {
+            return Some(format!("at {}: {} vs {}", path, kx, ky));
+        }
+        for slot in 0..a.get_node(x).arity() {
+            let (px, py) = (a.port(x, slot), b.port(y, slot));
+            let here = format!("{}/{}", path, slot);
+            match (px.is_null(), py.is_null()) {
+                (true, true) => continue,
+                (false, false) => {}
+                _ => return Some(format!("at {}: only one side is a free wire", here)),
+            }
+            if px.slot != py.slot {
+                return Some(format!(
+                    "at {}: wired into slot {} vs slot {}",
+                    here, px.slot, py.slot
+                ));
+            }
+            match (a_to_b.get(&px.node), b_to_a.get(&py.node)) {
+                (Some(&m), Some(&n)) if m == py.node && n == px.node => {}
+                (None, None) => {
+                    a_to_b.insert(px.node, py.node);
+                    b_to_a.insert(py.node, px.node);
+                    queue.push_back((px.node, py.node, here));
+                }
+                _ => return Some(format!("at {}: the wires close up differently", here)),
+            }
+        }
+    }
+
+    let rest_a = pieces(a, &a_to_b.keys().copied().collect());
+    let rest_b = pieces(b, &b_to_a.keys().copied().collect());
+    if rest_a != rest_b {
+        let first = rest_a.iter().find(|code| !rest_b.contains(code));
+        let second = rest_b.iter().find(|code| !rest_a.contains(code));
+        return Some(match (first, second) {
+            (Some(code), _) => format!("only the first net has the piece {}", code),
+            (_, Some(code)) => format!("only the second net has the piece {}", code),
+            // Same pieces, different counts
+            _ => format!("{} vs {} disconnected pieces", rest_a.len(), rest_b.len()),
+        });
+    }
+    None
+}
+
+// Canonical codes of the connected pieces not yet matched, sorted
+fn pieces(net: &Net, matched: &HashSet<NodeId>) -> Vec<String> {
+    let mut seen = matched.clone();
+    let mut codes = Vec::new();
+    for start in net.live_nodes() {
+        if !seen.insert(start) {
+            continue;
+        }
+        let mut piece = vec![start];
+        let mut i = 0;
+        while i < piece.len() {
+            let id = piece[i];
+            for slot in 0..net.get_node(id).arity() {
+                let peer = net.port(id, slot);
+                if !peer.is_null() && seen.insert(peer.node) {
+                    piece.push(peer.node);
+                }
+            }
+            i += 1;
+        }
+        // The code depends on where the walk starts, so take the smallest
+        let code = piece.iter().map(|&id| encode(net, id)).min().unwrap();
+        codes.push(code);
+    }
+    codes.sort();
+    codes
+}
+
+// The piece reachable from `start`, with nodes (and Dup labels) numbered
+// in the order a breadth-first walk meets them
+fn encode(net: &Net, start: NodeId) -> String {
+    let mut number = HashMap::from([(start, 0)]);
+    let mut labels: HashMap<u32, usize> = HashMap::new();
+    let mut order = vec![start];
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < order.len() {
+        let id = order[i];
+        let node = net.get_node(id);
+        let mut ports = Vec::new();
+        for slot in 0..node.arity() {
+            let peer = net.port(id, slot);
+            if peer.is_null() {
+                ports.push("-".to_string());
+                continue;
+            }
+            let next = number.len();
+            let n = *number.entry(peer.node).or_insert_with(|| {
+                order.push(peer.node);
+                next
+            });
+            ports.push(format!("{}:{}", n, peer.slot));
+        }
+        let kind = match node.kind() {
+            NodeKind::Dup(label) => {
+                let next = labels.len();
+                format!("DUP(#{})", labels.entry(label).or_insert(next))
+            }
+            kind => kind.to_string(),
+        };
+        out.push(format!("{}[{}]", kind, ports.join(" ")));
+        i += 1;
+    }
+    out.join(" ")
+}
@@ -0,0 +1,916 @@
+use std::fs;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use vice::batch::{self, Library};
+use vice::formatter;
+use vice::lambda;
+use vice::net::{Net, Node, NodeKind, Port, Redex};
+use vice::observer::{InteractionEvent, RuntimeObserver};
+use vice::parser;
+use vice::profile::Metric;
+use vice::readback::{Encoding, Shape, Value, read_value, read_value_lazy};
+use vice::runtime::{Budget, CancelToken, Progress, RunLimits, RunOutcome, Runtime};
+use vice::term::Term;
+use vice::verify;
+
+// Compiles a .hvm program, runs it on `threads` workers and reads `main`
+// back as a Church numeral
+fn run_hvm(text: &str, threads: usize) -> Value {
+    run_hvm_as(text, threads, &Shape::Number)
+}
+
+fn run_hvm_as(text: &str, threads: usize, shape: &Shape) -> Value {
+    let program = parser::parse(text).expect("the program parses");
+    let net = parser::compile(&program).expect("the program compiles");
+    let mut runtime = Runtime::new(net);
+    runtime.set_threads(threads);
+    assert_eq!(runtime.run(), Ok(RunOutcome::Normal));
+    read_value(runtime.get_net(), shape, Encoding::Church)
+}
+
+// Runs a lambda term and reads it back as a Church numeral
+fn run_lambda(text: &str) -> Value {
+    let term = lambda::parse(text).expect("the term parses");
+    let net = lambda::compile(&term).expect("the term compiles");
+    let mut runtime = Runtime::new(net);
+    assert_eq!(runtime.run(), Ok(RunOutcome::Normal));
+    read_value(runtime.get_net(), &Shape::Number, Encoding::Church)
+}
+
+fn example(name: &str) -> String {
+    fs::read_to_string(format!("examples/{}.hvm", name)).expect("the example exists")
+}
+
+#[test]
+fn church_arithmetic() {
+    let cases = [
+        ("2 + 3", 5),
+        ("7 - 3", 4),
+        ("6 * 7", 42),
+        ("pred 0", 0),
+        ("pred 5", 4),
+        ("succ (succ 0)", 2),
+        ("(λm n. n m) 2 3", 8),
+        ("let two = 2; two * two", 4),
+    ];
+    for (term, expected) in cases {
+        assert_eq!(run_lambda(term), Value::Number(expected), "{}", term);
+    }
+}
+
+#[test]
+fn factorial_of_five() {
+    let program = example("factorial").replace("main = fact 4", "main = fact 5");
+    assert_eq!(run_hvm(&program, 1), Value::Number(120));
+}
+
+#[test]
+fn examples_give_their_results() {
+    for (name, expected) in [("factorial", 24), ("fibonacci", 55), ("list_operation", 24)] {
+        assert_eq!(
+            run_hvm(&example(name), 1),
+            Value::Number(expected),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn unfoldings_of_one_definition_get_their_own_labels() {
+    let program = "twice f x = f (f x)\nmain = twice twice succ 0\n";
+    for threads in [1, 4] {
+        assert_eq!(
+            run_hvm(program, threads),
+            Value::Number(4),
+            "{} threads",
+            threads
+        );
+    }
+}
+
+#[test]
+fn a_label_at_the_end_of_the_range_is_refused() {
+    let mut net = Net::new();
+    assert!(net.claim_label(u32::MAX - 1).is_ok());
+    assert!(net.claim_label(u32::MAX).is_err());
+}
+
+// fact 4 handed to a host function that gives its argument back
+fn host_program() -> Runtime {
+    Runtime::new(host_net())
+}
+
+fn host_net() -> Net {
+    let text = example("factorial").replace("main = fact 4", "main = echo (fact 4)");
+    let program = parser::parse(&text).expect("the program parses");
+    let mut net = parser::compile_with_externs(&program, &["echo"]).expect("the program compiles");
+    net.register_host("echo", Arc::new(|term: &Term| Ok(term.clone())))
+        .expect("the host registers");
+    net
+}
+
+fn church(runtime: &Runtime) -> Value {
+    read_value(runtime.get_net(), &Shape::Number, Encoding::Church)
+}
+
+#[test]
+fn reducing_a_host_argument_burns_fuel() {
+    let mut full = host_program();
+    full.set_fuel(Some(u64::MAX));
+    assert_eq!(full.run(), Ok(RunOutcome::Normal));
+    assert_eq!(church(&full), Value::Number(24));
+    let used = full.get_stats().fuel_used;
+    // fact 4 takes far more than the flat price of the call itself
+    assert!(used > 1_000, "{} fuel used", used);
+
+    let mut short = host_program();
+    short.set_fuel(Some(used / 2));
+    assert_eq!(short.run(), Ok(RunOutcome::OutOfFuel));
+    assert!(short.get_stats().fuel_used <= used / 2);
+
+    // Topped up, the run finishes where it stopped
+    short.set_fuel(Some(used));
+    assert_eq!(short.run(), Ok(RunOutcome::Normal));
+    assert_eq!(church(&short), Value::Number(24));
+    assert_eq!(short.get_stats().fuel_used, used);
+}
+
+#[test]
+fn limits_stop_a_run_inside_a_host_argument() {
+    let mut full = host_program();
+    assert_eq!(full.run(), Ok(RunOutcome::Normal));
+    let steps = full.get_stats().steps;
+
+    let mut limited = host_program();
+    limited.set_limits(RunLimits {
+        max_steps: Some(steps / 2),
+        ..RunLimits::default()
+    });
+    assert_eq!(limited.run(), Ok(RunOutcome::StepLimit));
+    assert_eq!(limited.get_stats().steps, steps / 2);
+
+    limited.set_limits(RunLimits::default());
+    assert_eq!(limited.run(), Ok(RunOutcome::Normal));
+    assert_eq!(church(&limited), Value::Number(24));
+
+    let mut cancelled = host_program();
+    cancelled.set_limits(RunLimits {
+        max_steps: Some(steps / 2),
+        ..RunLimits::default()
+    });
+    assert_eq!(cancelled.run(), Ok(RunOutcome::StepLimit));
+    cancelled.cancel_token().cancel();
+    assert_eq!(cancelled.run(), Ok(RunOutcome::Cancelled));
+    assert_eq!(cancelled.get_stats().steps, steps / 2);
+}
+
+fn trace_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("vice-{}-{}.trace", name, std::process::id()))
+}
+
+#[test]
+fn a_recorded_trace_replays() {
+    let path = trace_path("replay");
+    let program = parser::parse(&example("factorial")).expect("the program parses");
+    let net = parser::compile(&program).expect("the program compiles");
+
+    let mut recorded = Runtime::new(net.clone());
+    let writer = recorded.record_trace(&path).expect("the trace is created");
+    assert_eq!(recorded.run(), Ok(RunOutcome::Normal));
+    assert_eq!(writer.lock().unwrap().error(), None);
+
+    let mut replayed = Runtime::new(net);
+    assert_eq!(replayed.replay(&path), Ok(recorded.get_stats().steps));
+    assert_eq!(church(&replayed), Value::Number(24));
+    fs::remove_file(path).ok();
+}
+
+#[test]
+fn a_trace_with_host_calls_is_refused() {
+    let path = trace_path("host");
+    let mut recorded = host_program();
+    recorded.record_trace(&path).expect("the trace is created");
+    assert_eq!(recorded.run(), Ok(RunOutcome::Normal));
+
+    let mut replayed = host_program();
+    let error = replayed
+        .replay(&path)
+        .expect_err("host answers are not in the trace");
+    assert!(error.contains("host function echo"), "{}", error);
+    // Nothing was replayed
+    assert_eq!(replayed.get_stats().steps, 0);
+    fs::remove_file(path).ok();
+}
+
+// The root reads a duplicator whose first copy goes into another one
+fn two_dups(first: u32, second: u32) -> Net {
+    let mut net = Net::new();
+    let dup = |label| Node::Dup {
+        label,
+        ports: [Port::null(); 3],
+    };
+    let (a, b) = (net.create_node(dup(first)), net.create_node(dup(second)));
+    net.connect(a, 1, b, 0);
+    net.set_root(a, 0);
+    net
+}
+
+#[test]
+fn dup_labels_compare_up_to_renaming() {
+    assert_eq!(verify::compare(&two_dups(1, 1), &two_dups(5, 5)), None);
+    assert_eq!(verify::compare(&two_dups(1, 2), &two_dups(6, 5)), None);
+    let divergence = verify::compare(&two_dups(1, 1), &two_dups(1, 2));
+    assert_eq!(divergence.as_deref(), Some("at root/0/1: DUP(1) vs DUP(2)"));
+}
+
+#[test]
+fn parallel_normal_forms_match_the_sequential_ones() {
+    for name in ["factorial", "fibonacci", "list_operation"] {
+        let program = parser::parse(&example(name)).expect("the program parses");
+        let net = parser::compile(&program).expect("the program compiles");
+        let reduce = |threads| {
+            let mut runtime = Runtime::new(net.clone());
+            runtime.set_threads(threads);
+            assert_eq!(runtime.run(), Ok(RunOutcome::Normal));
+            runtime
+        };
+        let (sequential, parallel) = (reduce(1), reduce(4));
+        assert_eq!(
+            verify::compare(sequential.get_net(), parallel.get_net()),
+            None,
+            "{}",
+            name
+        );
+        let balance = parallel.get_stats().balance;
+        assert!(
+            (1.0..=4.0).contains(&balance),
+            "{}: balance {}",
+            name,
+            balance
+        );
+        let speedup = parallel.get_stats().speedup;
+        assert!(
+            speedup > 0.0 && speedup <= 4.0 * 1.1,
+            "{}: speedup {}",
+            name,
+            speedup
+        );
+        assert_eq!(sequential.get_stats().speedup, 1.0);
+    }
+}
+
+#[test]
+fn every_schedule_reaches_the_same_normal_form() {
+    use verify::Schedule::{Parallel, Random, Sequential};
+    let limits = RunLimits {
+        max_steps: Some(1_000_000),
+        ..RunLimits::default()
+    };
+    for name in ["factorial", "fibonacci", "list_operation"] {
+        let program = parser::parse(&example(name)).expect("the program parses");
+        let net = parser::compile(&program).expect("the program compiles");
+        let schedules = [Sequential, Parallel(4), Random(1), Random(2)];
+        assert_eq!(
+            verify::verify(&net, &schedules, &limits),
+            Ok(()),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn compare_reports_where_two_nets_differ() {
+    let net = |text: &str| {
+        let term = lambda::parse(text).expect("the term parses");
+        lambda::compile(&term).expect("the term compiles")
+    };
+    // The same nodes; only the variable the inner binder hands out goes
+    // elsewhere
+    let divergence = verify::compare(&net("λa b. a b"), &net("λa b. b a"));
+    assert_eq!(
+        divergence.as_deref(),
+        Some("at root/0/1: wired into slot 0 vs slot 1")
+    );
+    assert_eq!(verify::compare(&net("λa b. a b"), &net("λx y. x y")), None);
+}
+
+#[test]
+fn terms_display_in_lambda_notation() {
+    assert_eq!(lambda::church(2).to_string(), "λf x. f (f x)");
+
+    // A host answer that cannot be built is quoted in the error
+    let text = example("factorial").replace("main = fact 4", "main = broken (fact 4)");
+    let program = parser::parse(&text).expect("the program parses");
+    let mut net =
+        parser::compile_with_externs(&program, &["broken"]).expect("the program compiles");
+    let answer = Term::lam("x", Term::app(Term::var("y"), Term::var("x")));
+    net.register_host("broken", Arc::new(move |_: &Term| Ok(answer.clone())))
+        .expect("the host registers");
+    let error = Runtime::new(net).run().expect_err("y is unbound");
+    assert!(error.contains("returned λx. y x:"), "{}", error);
+}
+
+const UNFORMATTED: &str = "f x = x\ng = f 1 // trailing\n\n\nh   =   λa.λb. a (g b)\n";
+
+#[test]
+fn formatting_is_idempotent() {
+    let program = parser::parse(UNFORMATTED).expect("the program parses");
+    let once = formatter::format(&program);
+    // The trailing comment stays on its definition's line
+    assert!(once.contains("\ng = f 1 // trailing\n"), "{}", once);
+    let again = formatter::format(&parser::parse(&once).expect("the output parses"));
+    assert_eq!(again, once);
+
+    for name in ["factorial", "fibonacci", "list_operation"] {
+        let once = formatter::format(&parser::parse(&example(name)).expect("the example parses"));
+        let again = formatter::format(&parser::parse(&once).expect("the output parses"));
+        assert_eq!(again, once, "{}", name);
+    }
+}
+
+#[test]
+fn fmt_check_fails_on_unformatted_files() {
+    let path = std::env::temp_dir().join(format!("vice-fmt-{}.hvm", std::process::id()));
+    fs::write(&path, UNFORMATTED).expect("the file is written");
+    let vice = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_vice"))
+            .args(args)
+            .arg(&path)
+            .output()
+            .expect("vice runs")
+    };
+
+    let check = vice(&["fmt", "--check"]);
+    assert_eq!(check.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&check.stdout).contains("is not formatted"));
+    // --check leaves the file alone
+    assert_eq!(fs::read_to_string(&path).unwrap(), UNFORMATTED);
+
+    assert!(vice(&["fmt"]).status.success());
+    assert_eq!(vice(&["fmt", "--check"]).status.code(), Some(0));
+
+    // A comment at the end of a line inside a definition has to move, and
+    // fmt says so
+    fs::write(&path, "f x =\n    g x // inner\n      x\ng x y = y\n").expect("the file is written");
+    let moved = vice(&["fmt"]);
+    assert!(moved.status.success());
+    let notes = String::from_utf8_lossy(&moved.stderr);
+    assert!(notes.contains("note: comment moved above `f`"), "{}", notes);
+    assert!(notes.contains(":2:9"), "{}", notes);
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "// inner\nf x = g x x\ng x y = y\n"
+    );
+    fs::remove_file(path).ok();
+}
+
+// Every name the prelude uses, defined by the program as something else
+const SHADOWING: &str = "
+true t f = f
+false t f = t
+not p = p
+and p q = p
+or p q = q
+succ n = n
+pred n = n
+is_zero n = n
+add m n = m
+sub m n = m
+mul m n = m
+le m n = m
+ge m n = m
+lt m n = m
+gt m n = m
+eq m n = m
+ne m n = m
+";
+
+#[test]
+fn operators_ignore_program_definitions_of_prelude_names() {
+    let arithmetic = [
+        ("3 - 1", 2),
+        ("3 + 4", 7),
+        ("3 * 4", 12),
+        ("(2 + 3) * (4 - 1)", 15),
+    ];
+    for (expr, expected) in arithmetic {
+        let text = format!("{}main = {}", SHADOWING, expr);
+        assert_eq!(run_hvm(&text, 1), Value::Number(expected), "{}", expr);
+    }
+    let comparisons = [
+        ("3 < 1", false),
+        ("1 < 3", true),
+        ("3 <= 3", true),
+        ("3 > 1", true),
+        ("1 >= 3", false),
+        ("2 == 2", true),
+        ("2 != 2", false),
+    ];
+    for (expr, expected) in comparisons {
+        let text = format!("{}main = {}", SHADOWING, expr);
+        assert_eq!(
+            run_hvm_as(&text, 1, &Shape::Boolean),
+            Value::Boolean(expected),
+            "{}",
+            expr
+        );
+    }
+    // The program's own definitions still win where it names them
+    let text = format!("{}main = pred 3", SHADOWING);
+    assert_eq!(run_hvm(&text, 1), Value::Number(3));
+}
+
+#[test]
+fn a_term_copying_a_copy_of_itself_is_an_error() {
+    for text in ["(λx. x x) 2", "(λx. x x) 3", "(λx. x x x) 2", "(λn. n n) 2"] {
+        let term = lambda::parse(text).expect("the term parses");
+        let net = lambda::compile(&term).expect("the term compiles");
+        let error = Runtime::new(net).run().expect_err(text);
+        assert!(error.contains("vicious circle"), "{}: {}", text, error);
+    }
+    // Copies of different terms are fine
+    assert_eq!(run_lambda("2 2"), Value::Number(4));
+}
+
+#[test]
+fn step_limits_count_across_run_for_slices() {
+    let program = parser::parse("loop x = loop x\nmain = loop 1").expect("the program parses");
+    let net = parser::compile(&program).expect("the program compiles");
+    let mut runtime = Runtime::new(net);
+    runtime.set_limits(RunLimits {
+        max_steps: Some(1000),
+        ..RunLimits::default()
+    });
+    let mut slices = 0;
+    let outcome = loop {
+        slices += 1;
+        assert!(
+            slices <= 10,
+            "still pending after {} steps",
+            runtime.get_stats().steps
+        );
+        match runtime.run_for(Budget::Steps(100)) {
+            Ok(Progress::Pending) => continue,
+            Ok(Progress::Done(outcome)) => break outcome,
+            Err(msg) => panic!("{}", msg),
+        }
+    };
+    assert_eq!(outcome, RunOutcome::StepLimit);
+    assert_eq!(runtime.get_stats().steps, 1000);
+
+    // A slice after the end starts a new run, and so does run()
+    assert_eq!(runtime.run_for(Budget::Steps(100)), Ok(Progress::Pending));
+    assert_eq!(runtime.run(), Ok(RunOutcome::StepLimit));
+    assert_eq!(runtime.get_stats().steps, 2100);
+}
+
+#[test]
+fn steps_and_batches_count_host_argument_reduction() {
+    let mut full = host_program();
+    assert_eq!(full.run(), Ok(RunOutcome::Normal));
+    let steps = full.get_stats().steps;
+
+    // step() takes the argument one interaction at a time
+    let mut stepped = host_program();
+    let mut calls = 0;
+    while stepped.step().expect("the step applies") {
+        calls += 1;
+    }
+    assert_eq!(calls, steps);
+    assert_eq!(church(&stepped), Value::Number(24));
+
+    let net = host_net();
+    let library = Library::from_net(&net);
+    let limits = RunLimits {
+        max_steps: Some(steps / 2),
+        ..RunLimits::default()
+    };
+    let results = batch::evaluate(&library, vec![net.clone()], 1, &limits, &CancelToken::new());
+    assert_eq!(results[0].outcome, Ok(RunOutcome::StepLimit));
+    assert_eq!(results[0].stats.steps, steps / 2);
+
+    let cancel = CancelToken::new();
+    cancel.cancel();
+    let results = batch::evaluate(&library, vec![net.clone()], 1, &limits, &cancel);
+    assert_eq!(results[0].outcome, Ok(RunOutcome::Cancelled));
+
+    let results = batch::evaluate(&library, vec![net], 1, &RunLimits::default(), &cancel);
+    assert_eq!(results[0].stats.steps, 0);
+    let results = batch::evaluate(
+        &library,
+        vec![host_net()],
+        1,
+        &RunLimits::default(),
+        &CancelToken::new(),
+    );
+    assert_eq!(results[0].outcome, Ok(RunOutcome::Normal));
+    assert_eq!(results[0].stats.steps, steps);
+}
+
+fn checkpoint_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("vice-{}-{}.checkpoint", name, std::process::id()))
+}
+
+// A checkpoint of the runtime without its stats line, which carries timings
+fn snapshot(runtime: &Runtime, path: &std::path::Path) -> Vec<String> {
+    runtime.checkpoint(path).expect("the checkpoint saves");
+    let text = fs::read_to_string(path).expect("the checkpoint reads");
+    text.lines()
+        .filter(|line| !line.starts_with("stats "))
+        .map(str::to_string)
+        .collect()
+}
+
+// sq 3 next to a duplicator that is erased before sq unfolds, so the next
+// fresh label is past every label left in the net
+fn erased_label_net() -> Net {
+    let program = parser::parse("sq x = x * x\nmain = sq 3").expect("the program parses");
+    let mut net = parser::compile(&program).expect("the program compiles");
+    let label = net.fresh_label();
+    let dup = net.create_node(Node::Dup {
+        label,
+        ports: [Port::null(); 3],
+    });
+    for slot in 0..3 {
+        let era = net.create_node(Node::Era { port: Port::null() });
+        net.connect(era, 0, dup, slot);
+    }
+    net
+}
+
+#[test]
+fn a_resumed_run_steps_like_an_uninterrupted_one() {
+    let (saved, left, right) = (
+        checkpoint_path("resume"),
+        checkpoint_path("resume-left"),
+        checkpoint_path("resume-right"),
+    );
+    let mut steps = 0;
+    while Runtime::new(erased_label_net()).run_for(Budget::Steps(steps + 1))
+        == Ok(Progress::Pending)
+    {
+        steps += 1;
+    }
+    // Stop after every step in turn and carry on from a checkpoint
+    for stop in 0..steps {
+        let mut original = Runtime::new(erased_label_net());
+        for _ in 0..stop {
+            assert!(original.step().expect("the step applies"));
+        }
+        original.checkpoint(&saved).expect("the checkpoint saves");
+        let mut resumed = Runtime::resume(&saved).expect("the checkpoint resumes");
+        loop {
+            assert_eq!(
+                snapshot(&original, &left),
+                snapshot(&resumed, &right),
+                "resumed after {} steps",
+                stop
+            );
+            let stepped = original.step().expect("the step applies");
+            assert_eq!(resumed.step(), Ok(stepped));
+            if !stepped {
+                break;
+            }
+        }
+        assert_eq!(original.get_stats().steps, resumed.get_stats().steps);
+    }
+    for path in [saved, left, right] {
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[test]
+fn resuming_needs_the_host_functions_back() {
+    let path = checkpoint_path("hosts");
+    let mut runtime = host_program();
+    for _ in 0..10 {
+        assert!(runtime.step().expect("the step applies"));
+    }
+    runtime.checkpoint(&path).expect("the checkpoint saves");
+
+    let err = Runtime::resume(&path).err().expect("resume refuses");
+    assert!(err.contains("host function echo"), "{}", err);
+
+    let mut resumed = Runtime::resume_with(&path, |net| {
+        net.register_host("echo", Arc::new(|term: &Term| Ok(term.clone())))
+    })
+    .expect("the checkpoint resumes");
+    assert_eq!(resumed.run(), Ok(RunOutcome::Normal));
+    assert_eq!(church(&resumed), Value::Number(24));
+    let _ = fs::remove_file(path);
+}
+
+#[test]
+fn an_operator_at_the_end_of_a_line_has_no_operand() {
+    use vice::diagnostic::Source;
+    let text = "main = g 3 +\ng x = x\nh = g 1 *\nk = h 2 ==\n";
+    let errors: Vec<_> = parser::check(Source::new("<input>", text), &[])
+        .iter()
+        .map(|e| (e.line, e.column, e.message.clone()))
+        .collect();
+    // Each definition after a broken one is still read: no name is undefined
+    assert_eq!(
+        errors,
+        [
+            (1, 12, "operator `+` has no right operand".to_string()),
+            (3, 9, "operator `*` has no right operand".to_string()),
+            (4, 9, "operator `==` has no right operand".to_string()),
+        ]
+    );
+}
+
+fn read_stream(text: &str, depth: usize) -> Value {
+    let program = parser::parse(text).expect("the program parses");
+    let mut runtime = Runtime::new(parser::compile(&program).expect("the program compiles"));
+    runtime.set_lazy(true);
+    let shape = Shape::List(Box::new(Shape::Number));
+    read_value_lazy(&mut runtime, &shape, Encoding::Scott, depth).expect("the read evaluates")
+}
+
+#[test]
+fn an_infinite_stream_reads_lazily_as_far_as_it_is_deep() {
+    let nats =
+        "zero z s = z\nnext n z s = s n\nnats k = λc e. c k (nats (next k))\nmain = nats zero";
+    let numbers = |n| (0..n).map(Value::Number).collect();
+    assert_eq!(read_stream(nats, 20), Value::Stream(numbers(3)));
+    assert_eq!(read_stream(nats, 40), Value::Stream(numbers(6)));
+
+    // Church numerals are not Scott ones: not a stream of numbers at all
+    let church = "nats k = λc e. c k (nats (succ k))\nmain = nats 0";
+    assert!(matches!(read_stream(church, 40), Value::Residual(_)));
+}
+
+#[test]
+fn factorial_profiles_as_folded_stacks() {
+    let program = parser::parse(&example("factorial")).expect("the program parses");
+    let mut runtime = Runtime::new(parser::compile(&program).expect("the program compiles"));
+    let profiler = runtime.start_profiling();
+    assert_eq!(runtime.run(), Ok(RunOutcome::Normal));
+    let folded = profiler.lock().unwrap().folded(Metric::Interactions);
+    let lines: Vec<&str> = folded.lines().collect();
+    assert_eq!(
+        lines[..5],
+        [
+            "(root);fact 4",
+            "(root);fact;pair 3",
+            "(root);fact;snd 2",
+            "(root);fact;snd;prelude.false 20",
+            "(root);fact;step 43",
+        ]
+    );
+    // Every interaction is on exactly one stack
+    let total: usize = lines
+        .iter()
+        .map(|line| line.rsplit_once(' ').unwrap().1.parse::<usize>().unwrap())
+        .sum();
+    assert_eq!(total, runtime.get_stats().steps);
+}
+
+#[test]
+fn debug_runs_report_stuck_pairs_and_vicious_circles() {
+    use vice::analysis::StuckTerm;
+    let con = || Node::Con {
+        tag: 0,
+        ports: [Port::null(); 3],
+    };
+    let mut net = Net::new();
+    // Each principal port points into the other's variable port
+    let (c1, c2) = (net.create_node(con()), net.create_node(con()));
+    net.connect(c1, 0, c2, 1);
+    net.connect(c2, 0, c1, 1);
+    // Principal to principal, but wired up behind the queue's back
+    let (p, q) = (net.create_node(con()), net.create_node(con()));
+    net.get_node_mut(p).set_port(0, Port::new(q, 0));
+    net.get_node_mut(q).set_port(0, Port::new(p, 0));
+
+    let mut runtime = Runtime::new(net);
+    runtime.set_debug(true);
+    let error = runtime.run().expect_err("the circle never reduces");
+    assert!(error.contains("vicious circle"), "{}", error);
+    assert_eq!(
+        runtime.get_stuck_terms(),
+        [
+            StuckTerm::Unqueued { a: p, b: q },
+            StuckTerm::ViciousCircle {
+                nodes: vec![c1, c2]
+            },
+        ]
+    );
+}
+
+fn example_runtime(name: &str) -> Runtime {
+    let program = parser::parse(&example(name)).expect("the program parses");
+    Runtime::new(parser::compile(&program).expect("the program compiles"))
+}
+
+#[test]
+fn limits_stop_a_run_that_can_be_carried_on() {
+    let mut runtime = example_runtime("factorial");
+    runtime.set_limits(RunLimits {
+        max_nodes: Some(60),
+        ..RunLimits::default()
+    });
+    assert_eq!(runtime.run(), Ok(RunOutcome::NodeLimit));
+    assert!(runtime.get_net().node_count() > 60);
+
+    // Lifting the limit finishes the same evaluation
+    runtime.set_limits(RunLimits::default());
+    assert_eq!(runtime.run(), Ok(RunOutcome::Normal));
+    assert_eq!(church(&runtime), Value::Number(24));
+
+    let program = parser::parse("loop x = loop x\nmain = loop 1").expect("the program parses");
+    let mut endless = Runtime::new(parser::compile(&program).expect("the program compiles"));
+    endless.set_limits(RunLimits {
+        timeout: Some(std::time::Duration::from_millis(20)),
+        ..RunLimits::default()
+    });
+    assert_eq!(endless.run(), Ok(RunOutcome::Timeout));
+    assert!(endless.get_stats().steps > 0);
+}
+
+#[test]
+fn another_thread_cancels_an_endless_run() {
+    let program = parser::parse("loop x = loop x\nmain = loop 1").expect("the program parses");
+    let net = parser::compile(&program).expect("the program compiles");
+    for threads in [1, 4] {
+        let mut runtime = Runtime::new(net.clone());
+        runtime.set_threads(threads);
+        let token = runtime.cancel_token();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            token.cancel();
+        });
+        assert_eq!(
+            runtime.run(),
+            Ok(RunOutcome::Cancelled),
+            "{} threads",
+            threads
+        );
+        canceller.join().expect("the canceller finishes");
+        // It stopped cleanly, with the work done so far counted
+        assert!(runtime.get_stats().steps > 0, "{} threads", threads);
+        assert!(runtime.get_net().redex_count() > 0, "{} threads", threads);
+    }
+}
+
+// Every interaction an observer is told about, and the live node count its
+// events add up to
+#[derive(Default)]
+struct Log {
+    before: Vec<Redex>,
+    after: Vec<Redex>,
+    live: isize,
+}
+
+impl RuntimeObserver for Log {
+    fn before_interaction(&mut self, _net: &Net, redex: Redex, _kinds: &[NodeKind; 2]) {
+        self.before.push(redex);
+    }
+
+    fn after_interaction(&mut self, _net: &Net, event: &InteractionEvent) {
+        self.after.push(event.redex);
+        self.live += event.created.len() as isize - event.freed.len() as isize;
+    }
+}
+
+#[test]
+fn observers_see_every_interaction_of_run_and_step() {
+    let watch = |runtime: &mut Runtime| {
+        let log = Arc::new(Mutex::new(Log::default()));
+        runtime.add_observer(log.clone());
+        log
+    };
+    let mut run = example_runtime("factorial");
+    let start = run.get_net().node_count() as isize;
+    let ran = watch(&mut run);
+    assert_eq!(run.run(), Ok(RunOutcome::Normal));
+
+    let mut step = example_runtime("factorial");
+    let stepped = watch(&mut step);
+    while step.step().expect("the step applies") {}
+
+    let (ran, stepped) = (ran.lock().unwrap(), stepped.lock().unwrap());
+    assert_eq!(ran.before.len(), run.get_stats().steps);
+    assert_eq!(ran.before, ran.after);
+    assert_eq!(ran.after, stepped.after);
+    assert_eq!(start + ran.live, run.get_net().node_count() as isize);
+}
+
+#[test]
+fn sampled_runs_export_csv_and_timings() {
+    let mut runtime = example_runtime("factorial");
+    runtime.set_sampling(Some(100));
+    assert_eq!(runtime.run(), Ok(RunOutcome::Normal));
+    let stats = runtime.get_stats();
+    let steps: Vec<usize> = stats.samples.iter().map(|s| s.step).collect();
+    let every_hundred: Vec<usize> = (1..=stats.steps / 100).map(|n| n * 100).collect();
+    assert_eq!(steps, every_hundred);
+
+    let path = std::env::temp_dir().join(format!("vice-samples-{}.csv", std::process::id()));
+    stats.write_samples_csv(&path).expect("the csv is written");
+    let csv = fs::read_to_string(&path).expect("the csv reads");
+    fs::remove_file(&path).ok();
+    let mut lines = csv.lines();
+    assert_eq!(lines.next(), Some("step,live_nodes,queue_len"));
+    let first = &stats.samples[0];
+    let row = format!("100,{},{}", first.live_nodes, first.queue_len);
+    assert_eq!(lines.next(), Some(row.as_str()));
+    assert_eq!(lines.count(), stats.samples.len() - 1);
+
+    assert!(stats.ref_time + stats.rewrite_time <= stats.wall_time);
+    assert!(stats.mips() > 0.0);
+}
+
+#[test]
+fn breakpoints_hand_control_back_and_the_run_carries_on() {
+    use vice::breakpoint::Breakpoint;
+    use vice::interaction::Rule;
+    let mut runtime = example_runtime("factorial");
+    runtime.add_breakpoint(Breakpoint::Expand("step".to_string()));
+    assert_eq!(runtime.run(), Ok(RunOutcome::Breakpoint));
+    let hit = runtime.breakpoint_hit().expect("the hit is kept");
+    assert!(hit.kinds.contains(&NodeKind::Ref("step".to_string())));
+    let at = runtime.get_stats().steps;
+
+    // step unfolds once and is then copied, so the rest runs through
+    assert_eq!(runtime.run(), Ok(RunOutcome::Normal));
+    assert!(runtime.breakpoint_hit().is_none());
+    assert_eq!(church(&runtime), Value::Number(24));
+    assert!(at < runtime.get_stats().steps);
+
+    let mut runtime = example_runtime("factorial");
+    runtime.add_breakpoint(Breakpoint::Rule(Rule::Commute));
+    assert_eq!(runtime.run(), Ok(RunOutcome::Breakpoint));
+    let hit = runtime.breakpoint_hit().expect("the hit is kept");
+    assert_eq!(
+        Rule::between(&hit.kinds[0], &hit.kinds[1]),
+        Some(Rule::Commute)
+    );
+
+    let mut runtime = example_runtime("factorial");
+    let outcome = runtime.run_until(|net, _| net.node_count() > 100);
+    assert_eq!(outcome, Ok(RunOutcome::Breakpoint));
+    // An unfolding adds many nodes at once, so the count may overshoot
+    assert!(runtime.get_net().node_count() > 100);
+    assert!(runtime.get_net().redex_count() > 0);
+}
+
+#[test]
+fn a_batch_evaluates_nets_over_one_library_in_order() {
+    let program = parser::parse(&example("factorial")).expect("the program parses");
+    let library = Library::from_net(&parser::compile(&program).expect("the program compiles"));
+    let nets: Vec<Net> = (1..=5)
+        .map(|n| {
+            let text = format!("main = fact {}", n);
+            let program = parser::parse(&text).expect("the expression parses");
+            parser::compile_with_externs(&program, &["fact"]).expect("the expression compiles")
+        })
+        .collect();
+    let limits = RunLimits::default();
+    let cancel = CancelToken::new();
+
+    let pooled = batch::evaluate(&library, nets.clone(), 4, &limits, &cancel);
+    let alone = batch::evaluate(&library, nets, 1, &limits, &cancel);
+    let factorials = [1, 2, 6, 24, 120];
+    for ((result, single), expected) in pooled.iter().zip(&alone).zip(factorials) {
+        assert_eq!(result.outcome, Ok(RunOutcome::Normal));
+        let value = read_value(&result.net, &Shape::Number, Encoding::Church);
+        assert_eq!(value, Value::Number(expected));
+        // Each net is reduced the same way whichever worker takes it
+        assert_eq!(result.stats.steps, single.stats.steps);
+    }
+}
+
+#[test]
+fn diagnostics_render_with_a_caret_and_as_json() {
+    use vice::diagnostic::Source;
+    let text = "square x = x * x\nmain = sqaure 3\n";
+    let errors = parser::check(Source::new("area.hvm", text), &[]);
+    assert_eq!(errors.len(), 1);
+    let rendered = [
+        "error: undefined name `sqaure`",
+        " --> area.hvm:2:8",
+        "  |",
+        "2 | main = sqaure 3",
+        "  |        ^^^^^^",
+        "",
+    ];
+    assert_eq!(errors[0].render(), rendered.join("\n"));
+    assert_eq!(
+        errors[0].to_json(),
+        r#"{"severity":"error","file":"area.hvm","line":2,"column":8,"end_line":2,"end_column":14,"message":"undefined name `sqaure`"}"#
+    );
+
+    // A reference that fails at run time points back at where it was written
+    let program = parser::parse_source(Source::new("area.hvm", "main = echo 3\n"))
+        .expect("the program parses");
+    let net = parser::compile_with_externs(&program, &["echo"]).expect("the program compiles");
+    let error = Runtime::new(net).run().expect_err("echo is not registered");
+    assert!(
+        error.ends_with("area.hvm:1:8: undefined function `echo`, referenced in `main`"),
+        "{}",
+        error
+    );
+}